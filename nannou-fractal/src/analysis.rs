@@ -0,0 +1,297 @@
+use crate::koch::interpreter::{interpret, measure, Segment};
+use crate::koch::Koch;
+
+use glam::{IVec2, IVec3, Vec2, Vec3};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+const MAX_HALVINGS: i32 = 12; // smallest box of the box counting is the extent / 2^12
+pub const MAX_SEGMENTS: usize = 100_000; // most segments the dimension and crossings are found for
+
+// Measurements of the drawing of one level of an L-system
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub level: usize,
+    pub segments: usize,
+    pub length: f32, // total length of the path
+    pub bounds: Option<(Vec3, Vec3)>,
+    pub dimension: Option<f32>,   // box-counting dimension
+    pub crossings: Option<usize>, // segments crossing each other in the xy plane
+}
+
+// Stats of the current level of koch. Past MAX_SEGMENTS the dimension and crossings take too
+// long to find and are left out, and the segments are not kept.
+pub fn analyse(koch: &Koch, length: f32) -> Stats {
+    let extent = measure(koch, length);
+    let segments = (extent.segments <= MAX_SEGMENTS).then(|| interpret(koch, length).segments);
+    Stats {
+        level: koch.get_n(),
+        segments: extent.segments,
+        length: extent.length,
+        bounds: extent.bounds,
+        dimension: segments.as_deref().and_then(dimension),
+        crossings: segments.as_deref().map(crossings),
+    }
+}
+
+// Stats of every level of koch up to its current one
+pub fn levels(koch: &Koch, length: f32) -> Vec<Stats> {
+    let mut stats = Vec::new();
+    extend(&mut stats, koch, length);
+    stats
+}
+
+// Brings the stats of the levels of koch up to its current one, keeping the levels already
+// found and dropping the ones past it
+pub fn extend(stats: &mut Vec<Stats>, koch: &Koch, length: f32) {
+    let n = koch.get_n();
+    stats.truncate(n + 1);
+    let mut koch = koch.clone();
+    for level in stats.len()..=n {
+        koch.set_n(level);
+        stats.push(analyse(&koch, length));
+    }
+}
+
+// Box-counting dimension: the slope of log(boxes hit) over log(1 / box size), with boxes halving
+// from half the extent of the drawing down to the mean segment length. Below that every
+// drawing looks like straight lines.
+pub fn dimension(segments: &[Segment]) -> Option<f32> {
+    let ends = segments.iter().flat_map(|s| [s.start, s.end]);
+    let (min, max) = ends.fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(p), max.max(p)),
+    );
+    let extent = (max - min).max_element();
+    let total: f32 = segments.iter().map(|s| s.start.distance(s.end)).sum();
+    if segments.is_empty() || extent <= 0.0 {
+        return None;
+    }
+    let mean = total / segments.len() as f32;
+
+    let mut samples = Vec::new(); // (log(1 / size), log(boxes))
+    for k in 1..=MAX_HALVINGS {
+        let size = extent / 2f32.powi(k);
+        if size < mean {
+            break;
+        }
+        let mut boxes = HashSet::new();
+        for segment in segments {
+            // sampling at half the box size hits every box the segment passes through
+            let steps = (segment.start.distance(segment.end) / size * 2.0).ceil() as usize;
+            for i in 0..=steps {
+                let p = segment
+                    .start
+                    .lerp(segment.end, i as f32 / steps.max(1) as f32);
+                let b = ((p - min) / size).floor();
+                boxes.insert(IVec3::new(b.x as i32, b.y as i32, b.z as i32));
+            }
+        }
+        samples.push(((1.0 / size).ln(), (boxes.len() as f32).ln()));
+    }
+    if samples.len() < 2 {
+        return None;
+    }
+
+    // least squares fit of a line through the samples
+    let n = samples.len() as f32;
+    let (sx, sy) = samples
+        .iter()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mx, my) = (sx / n, sy / n);
+    let (sxy, sxx) = samples.iter().fold((0.0, 0.0), |(sxy, sxx), (x, y)| {
+        (sxy + (x - mx) * (y - my), sxx + (x - mx) * (x - mx))
+    });
+    Some(sxy / sxx)
+}
+
+// Number of pairs of segments crossing each other in the xy plane. Segments that only touch,
+// such as consecutive ones or a branch and its stem, do not count.
+pub fn crossings(segments: &[Segment]) -> usize {
+    let total: f32 = segments.iter().map(|s| s.start.distance(s.end)).sum();
+    let cell = (total / segments.len().max(1) as f32).max(f32::EPSILON);
+    let flat: Vec<(Vec2, Vec2)> = segments
+        .iter()
+        .map(|s| (s.start.truncate(), s.end.truncate()))
+        .filter(|(a, b)| a != b)
+        .collect();
+
+    // cells of a grid covered by the bounding box of each segment
+    let range = |(a, b): (Vec2, Vec2)| {
+        let lo = (a.min(b) / cell).floor();
+        let hi = (a.max(b) / cell).floor();
+        (
+            IVec2::new(lo.x as i32, lo.y as i32),
+            IVec2::new(hi.x as i32, hi.y as i32),
+        )
+    };
+    let mut grid: HashMap<IVec2, Vec<usize>> = HashMap::new();
+    for (i, &segment) in flat.iter().enumerate() {
+        let (lo, hi) = range(segment);
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                grid.entry(IVec2::new(x, y)).or_default().push(i);
+            }
+        }
+    }
+
+    let tolerance = cell * 1e-4;
+    let mut count = 0;
+    for (&at, indices) in grid.iter() {
+        for (k, &i) in indices.iter().enumerate() {
+            for &j in indices[k + 1..].iter() {
+                // a pair sharing several cells is counted in the first of them
+                let (lo_i, _) = range(flat[i]);
+                let (lo_j, _) = range(flat[j]);
+                if lo_i.max(lo_j) == at && cross(flat[i], flat[j], tolerance) {
+                    count += 1;
+                }
+            }
+        }
+    }
+    count
+}
+
+// Whether the segments cross away from their ends
+fn cross((a, b): (Vec2, Vec2), (c, d): (Vec2, Vec2), tolerance: f32) -> bool {
+    // signed distances of the ends of one segment from the line through the other
+    let side = |p: Vec2, q: Vec2, r: Vec2| (q - p).perp_dot(r - p) / p.distance(q);
+    let (c_side, d_side) = (side(a, b, c), side(a, b, d));
+    let (a_side, b_side) = (side(c, d, a), side(c, d, b));
+    let apart = |u: f32, v: f32| u * v < 0.0 && u.abs() > tolerance && v.abs() > tolerance;
+    apart(c_side, d_side) && apart(a_side, b_side)
+}
+
+// Cells of a table of stats, the header first and then one level per row
+pub fn rows(stats: &[Stats]) -> Vec<[String; 6]> {
+    let header = [
+        "n",
+        "segments",
+        "length",
+        "bounds",
+        "dimension",
+        "crossings",
+    ];
+    let mut rows = vec![header.map(String::from)];
+    for s in stats {
+        let bounds = match s.bounds {
+            Some((min, max)) if (max - min).z.abs() > f32::EPSILON => {
+                let size = max - min;
+                format!("{:.1}x{:.1}x{:.1}", size.x, size.y, size.z)
+            }
+            Some((min, max)) => format!("{:.1}x{:.1}", max.x - min.x, max.y - min.y),
+            None => "-".to_string(),
+        };
+        let dimension = match s.dimension {
+            Some(d) => format!("{:.3}", d),
+            None => "-".to_string(),
+        };
+        let crossings = match s.crossings {
+            Some(c) => c.to_string(),
+            None => "-".to_string(),
+        };
+        rows.push([
+            s.level.to_string(),
+            s.segments.to_string(),
+            format!("{:.1}", s.length),
+            bounds,
+            dimension,
+            crossings,
+        ]);
+    }
+    rows
+}
+
+// The rows as text with right aligned columns
+pub fn table(stats: &[Stats]) -> String {
+    let rows = rows(stats);
+    let mut widths = [0; 6];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in rows.iter() {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        writeln!(table, "{}", cells.join("  ")).unwrap();
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::fixtures::{builtin_koch, koch};
+
+    #[test]
+    fn dimensions() {
+        let close = |koch: &Koch, expected: f32| {
+            let d = analyse(koch, 1.0).dimension.unwrap();
+            assert!((d - expected).abs() < 0.1, "{} != {}", d, expected);
+        };
+        close(&koch("F", &["F => FF"], 8), 1.0);
+        // five segments a third as long
+        close(&builtin_koch("caret"), 5f32.ln() / 3f32.ln());
+        // eight segments a quarter as long
+        let mut cyclone = builtin_koch("cyclone");
+        cyclone.set_n(4);
+        close(&cyclone, 1.5);
+        // three segments half as long
+        close(&builtin_koch("sierpinski"), 3f32.ln() / 2f32.ln());
+        assert_eq!(analyse(&koch("F", &[], 0), 1.0).dimension, None);
+    }
+
+    #[test]
+    fn stats() {
+        let stats = levels(&builtin_koch("tree_a"), 1.0);
+        let segments: Vec<_> = stats.iter().map(|s| s.segments).collect();
+        assert_eq!(segments, vec![1, 5, 25, 125, 625, 3125]);
+        assert_eq!(stats[2].length, 25.0);
+        let (min, max) = stats[0].bounds.unwrap();
+        assert_eq!((min, max), (Vec3::ZERO, Vec3::X));
+        // branches meet their stems without crossing them
+        assert!(stats.iter().all(|s| s.crossings == Some(0)));
+        assert_eq!(table(&stats).lines().count(), 7);
+    }
+
+    #[test]
+    fn cache() {
+        let mut koch = builtin_koch("dragon");
+        koch.set_n(4);
+        let mut stats = levels(&koch, 1.0);
+        let first = stats.clone();
+        koch.set_n(7);
+        extend(&mut stats, &koch, 1.0);
+        assert_eq!(stats.len(), 8);
+        assert_eq!(stats[..5], first[..]);
+        assert_eq!(stats[7], analyse(&koch, 1.0));
+        koch.set_n(2);
+        extend(&mut stats, &koch, 1.0);
+        assert_eq!(stats[..], first[..3]);
+
+        // the dimension and crossings of large levels are skipped
+        koch.set_n(17);
+        let stats = analyse(&koch, 1.0);
+        assert!(stats.segments > MAX_SEGMENTS);
+        assert_eq!((stats.dimension, stats.crossings), (None, None));
+        assert_eq!(rows(&[stats])[1][4..], ["-", "-"]);
+    }
+
+    #[test]
+    fn crossing() {
+        // the last side crosses the first one halfway
+        let stats = analyse(&koch("F(2)+F+F+F(2)", &[], 0), 1.0);
+        assert_eq!(stats.crossings, Some(1));
+        // a closed square only touches itself at its corners
+        assert_eq!(analyse(&koch("F+F+F+F", &[], 0), 1.0).crossings, Some(0));
+        // a star drawn in one stroke crosses itself five times
+        let mut star = koch("F+F+F+F+F", &[], 0);
+        star.set_delta(144f32.to_radians());
+        assert_eq!(analyse(&star, 1.0).crossings, Some(5));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::koch::KochSpec;
+use crate::preset;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+/// Draws L-systems with a turtle
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// Preset to start with, by name or as a path to a .toml file
+    #[arg(short, long)]
+    pub preset: Option<String>,
+    /// Number of iterations, instead of the preset's
+    #[arg(short = 'n', long)]
+    pub iterations: Option<usize>,
+    /// Turning angle in degrees, instead of the preset's
+    #[arg(short, long)]
+    pub angle: Option<f32>,
+    /// Length of a step, in pixels before the drawing is fit to the window
+    #[arg(short, long, default_value_t = 4.0)]
+    pub length: f32,
+    /// Seed for picking among stochastic productions, random if not given
+    #[arg(short, long)]
+    pub seed: Option<u64>,
+    /// Size of the window and of rendered images, as WIDTHxHEIGHT
+    #[arg(long, default_value = "1024x768", value_parser = parse_size)]
+    pub size: (u32, u32),
+    /// Writes the finished drawing to a .png or .svg file
+    #[arg(short, long)]
+    pub render: Option<PathBuf>,
+    /// Exits after --render instead of opening the window
+    #[arg(long, requires = "render")]
+    pub headless: bool,
+    /// Steps the turtle draws per frame
+    #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub speed: usize,
+    /// Records the drawing as numbered PNGs into this directory, then exits
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Records the finished drawing of each level instead of every frame
+    #[arg(long, requires = "record")]
+    pub levels: bool,
+    /// Records every n-th frame
+    #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub stride: usize,
+    /// Encodes the recorded frames into this GIF
+    #[arg(long, requires = "record")]
+    pub gif: Option<PathBuf>,
+    /// Milliseconds each frame is shown in the GIF
+    #[arg(long, default_value_t = 40)]
+    pub duration: u32,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Prints the stats of every level of a preset
+    Stats {
+        /// Preset name or path to a .toml file
+        preset: String,
+        /// Last level, instead of the preset's iterations
+        n: Option<usize>,
+    },
+}
+
+const MAX_SIZE: u32 = 16384; // pixels along each side, past any window or texture
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let error = || format!("bad size `{}`, expected WIDTHxHEIGHT up to {}", s, MAX_SIZE);
+    let (w, h) = s.split_once('x').ok_or_else(error)?;
+    let side = |n: &u32| (1..=MAX_SIZE).contains(n);
+    match (w.parse(), h.parse()) {
+        (Ok(w), Ok(h)) if side(&w) && side(&h) => Ok((w, h)),
+        _ => Err(error()),
+    }
+}
+
+// The presets directory next to the executable or above it, as the app finds it
+pub fn presets_dir() -> Option<PathBuf> {
+    nannou::app::find_assets_path()
+        .ok()
+        .map(|p| p.join("presets"))
+}
+
+// The directory of iterated function systems, next to the presets
+pub fn ifs_dir() -> Option<PathBuf> {
+    nannou::app::find_assets_path().ok().map(|p| p.join("ifs"))
+}
+
+// A preset by name, or loaded from the given .toml file
+pub fn find(name: &str, presets: &[KochSpec]) -> Result<KochSpec, String> {
+    if name.ends_with(".toml") {
+        return preset::load(Path::new(name)).map_err(|e| format!("{}: {}", name, e));
+    }
+    presets
+        .iter()
+        .find(|spec| spec.name == name)
+        .cloned()
+        .ok_or(format!("unknown preset `{}`", name))
+}
+
+impl Args {
+    // The preset to start with, the first one if none is given, with the overrides applied
+    pub fn spec(&self, presets: &[KochSpec]) -> Result<KochSpec, String> {
+        let mut spec = match &self.preset {
+            Some(name) => find(name, presets)?,
+            None => presets[0].clone(),
+        };
+        if let Some(n) = self.iterations {
+            spec.iterations = n;
+        }
+        if let Some(angle) = self.angle {
+            spec.angle = angle;
+        }
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("nannou-fractal").chain(args.split_whitespace()))
+    }
+
+    #[test]
+    fn arguments() {
+        let args =
+            parse("-p tree_a -n 3 --angle 30 --size 640x480 -r tree.png --headless").unwrap();
+        assert_eq!(args.size, (640, 480));
+        assert_eq!(args.render, Some(PathBuf::from("tree.png")));
+        let spec = args.spec(&preset::builtin()).unwrap();
+        assert_eq!(
+            (spec.name.as_str(), spec.iterations, spec.angle),
+            ("tree_a", 3, 30.0)
+        );
+
+        let args = parse("").unwrap();
+        assert_eq!(args.spec(&preset::builtin()).unwrap(), preset::builtin()[0]);
+        assert!(parse("-p nope").unwrap().spec(&preset::builtin()).is_err());
+
+        assert!(parse("--size 640").is_err());
+        assert!(parse("--size 0x480").is_err());
+        assert!(parse("--size 70000x70000").is_err());
+        // headless only makes sense with something to render
+        assert!(parse("--headless").is_err());
+        let args = parse("--record frames --levels --gif tree.gif --stride 4").unwrap();
+        assert_eq!((args.levels, args.stride, args.duration), (true, 4, 40));
+        assert!(parse("--record frames --stride 0").is_err());
+        assert!(parse("--gif tree.gif").is_err());
+        assert!(matches!(
+            parse("stats dragon 8").unwrap().command,
+            Some(Command::Stats { n: Some(8), .. })
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::style::Style;
+
+use glam::{DVec2, Vec2};
+use nannou::image::{Rgb, RgbImage};
+use rayon::prelude::*;
+use std::f64::consts::LN_2;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const BAILOUT: f64 = 256.0; // escape radius, well past 2 so that the smooth count is smooth
+const ITERATIONS: u32 = 256; // before a point counts as inside the set
+const MAX_ITERATIONS: u32 = 1 << 20; // past this a pass over the set takes minutes
+const TILE: u32 = 64; // side of the square tiles rendered in parallel
+const BLOCKS: [u32; 3] = [16, 4, 1]; // side of the blocks of pixels sharing a sample, by pass
+const WIDTH: f64 = 3.5; // of the plane shown before zooming
+const MIN_SCALE: f64 = 1e-14; // units per pixel, past which f64 runs out of precision
+const BAND: f32 = 32.0; // iterations per run through the palette
+const PALETTE: [&str; 5] = ["#000764", "#206bcb", "#edffff", "#ffaa00", "#000764"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Formula {
+    Mandelbrot,
+    Julia(DVec2), // the constant added at each step
+    BurningShip,
+}
+
+impl fmt::Display for Formula {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Formula::Mandelbrot => write!(f, "mandelbrot"),
+            Formula::Julia(c) => write!(f, "julia {:.4} {:+.4}i", c.x, c.y),
+            Formula::BurningShip => write!(f, "burning ship"),
+        }
+    }
+}
+
+impl Formula {
+    // Smooth iteration count of the point, or None if it has not escaped after max iterations.
+    // The count grows by one with each iteration it takes to escape, and in between by how
+    // far past the bailout the last one landed.
+    pub fn escape(self, p: DVec2, max: u32) -> Option<f32> {
+        let (mut z, c) = match self {
+            Formula::Mandelbrot => (DVec2::ZERO, p),
+            Formula::Julia(c) => (p, c),
+            // upside down, so that the ship floats upright
+            Formula::BurningShip => (DVec2::ZERO, DVec2::new(p.x, -p.y)),
+        };
+        for n in 0..max {
+            if self == Formula::BurningShip {
+                z = z.abs();
+            }
+            z = DVec2::new(z.x * z.x - z.y * z.y, 2.0 * z.x * z.y) + c;
+            let r2 = z.length_squared();
+            if r2 > BAILOUT * BAILOUT {
+                return Some((n as f64 + 1.0 - (r2.ln() / 2.0).ln() / LN_2) as f32);
+            }
+        }
+        None
+    }
+
+    // Centre of the view showing the whole set
+    fn home(self) -> DVec2 {
+        match self {
+            Formula::Mandelbrot => DVec2::new(-0.6, 0.0),
+            Formula::Julia(_) => DVec2::ZERO,
+            Formula::BurningShip => DVec2::new(-0.4, 0.5),
+        }
+    }
+}
+
+// An escape-time fractal rendered on the CPU, tile by tile. Each pass samples one pixel of
+// every block and fills the block with it, the blocks shrinking from pass to pass until every
+// pixel is sampled.
+#[derive(Debug, Clone)]
+pub struct Escape {
+    formula: Formula,
+    center: DVec2, // point of the plane in the middle of the image
+    scale: f64,    // units per pixel
+    iterations: u32,
+    width: u32,
+    height: u32,
+    values: Vec<Option<f32>>, // smooth iteration count of each pixel, None inside the set
+    pass: usize,              // index of the pass in progress, BLOCKS.len() when done
+    tile: usize,              // next tile of the pass
+    palette: Style,
+}
+
+impl Escape {
+    pub fn new(formula: Formula, width: u32, height: u32) -> Self {
+        let mut escape = Self {
+            formula,
+            center: DVec2::ZERO,
+            scale: 1.0,
+            iterations: ITERATIONS,
+            width,
+            height,
+            values: vec![None; width as usize * height as usize],
+            pass: 0,
+            tile: 0,
+            palette: Style::new(&PALETTE.map(String::from)).unwrap(),
+        };
+        escape.reset();
+        escape
+    }
+
+    pub fn get_formula(&self) -> Formula {
+        self.formula
+    }
+
+    pub fn set_formula(&mut self, formula: Formula) {
+        self.formula = formula;
+        self.reset();
+    }
+
+    pub fn get_iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    pub fn set_iterations(&mut self, iterations: u32) {
+        self.iterations = iterations.clamp(1, MAX_ITERATIONS);
+        self.restart();
+    }
+
+    // Magnification relative to the view of the whole set
+    pub fn get_zoom(&self) -> f64 {
+        WIDTH / (self.scale * self.width.min(self.height) as f64)
+    }
+
+    // Shows the whole set
+    pub fn reset(&mut self) {
+        self.center = self.formula.home();
+        self.scale = WIDTH / self.width.min(self.height) as f64;
+        self.restart();
+    }
+
+    // Starts over at the coarsest pass, e.g. after the view changed
+    fn restart(&mut self) {
+        self.pass = 0;
+        self.tile = 0;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pass == BLOCKS.len()
+    }
+
+    // Renders at the new size, ignoring the zero size of a minimised window
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 || (width, height) == (self.width, self.height) {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.values = vec![None; width as usize * height as usize];
+        self.restart();
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    // Point of the plane at pos, in pixels from the middle of the image with y up
+    pub fn point(&self, pos: Vec2) -> DVec2 {
+        self.center + DVec2::new(pos.x as f64, pos.y as f64) * self.scale
+    }
+
+    // Moves the view along with a drag of delta pixels
+    pub fn pan(&mut self, delta: Vec2) {
+        self.center -= DVec2::new(delta.x as f64, delta.y as f64) * self.scale;
+        self.restart();
+    }
+
+    // Zooms in by factor, keeping the point at pos in place
+    pub fn zoom_at(&mut self, pos: Vec2, factor: f64) {
+        let p = self.point(pos);
+        self.scale = (self.scale / factor).max(MIN_SCALE);
+        self.center = p - DVec2::new(pos.x as f64, pos.y as f64) * self.scale;
+        self.restart();
+    }
+
+    // Tiles covering the image as (x, y, width, height), middle first so that the part looked
+    // at sharpens first
+    fn tiles(&self) -> Vec<(u32, u32, u32, u32)> {
+        let mut tiles = Vec::new();
+        for y in (0..self.height).step_by(TILE as usize) {
+            for x in (0..self.width).step_by(TILE as usize) {
+                let (w, h) = (TILE.min(self.width - x), TILE.min(self.height - y));
+                tiles.push((x, y, w, h));
+            }
+        }
+        let middle = |&(x, y, w, h): &(u32, u32, u32, u32)| {
+            let dx = (2 * x + w) as i64 - self.width as i64;
+            let dy = (2 * y + h) as i64 - self.height as i64;
+            dx * dx + dy * dy
+        };
+        tiles.sort_by_key(middle);
+        tiles
+    }
+
+    fn sample(&self, x: u32, y: u32) -> Option<f32> {
+        let pos = Vec2::new(
+            x as f32 + 0.5 - self.width as f32 / 2.0,
+            self.height as f32 / 2.0 - y as f32 - 0.5,
+        );
+        self.formula.escape(self.point(pos), self.iterations)
+    }
+
+    // Values of a tile in the pass in progress. Pixels sampled by the last pass keep their
+    // values.
+    fn render_tile(&self, (x0, y0, w, h): (u32, u32, u32, u32)) -> Vec<Option<f32>> {
+        let block = BLOCKS[self.pass];
+        let last = self.pass.checked_sub(1).map(|p| BLOCKS[p]);
+        let mut values = vec![None; (w * h) as usize];
+        for y in (0..h).step_by(block as usize) {
+            for x in (0..w).step_by(block as usize) {
+                let (px, py) = (x0 + x, y0 + y);
+                let value = match last {
+                    Some(last) if px % last == 0 && py % last == 0 => {
+                        self.values[self.index(px, py)]
+                    }
+                    _ => self.sample(px, py),
+                };
+                for by in y..(y + block).min(h) {
+                    for bx in x..(x + block).min(w) {
+                        values[(by * w + bx) as usize] = value;
+                    }
+                }
+            }
+        }
+        values
+    }
+
+    // Renders tiles, a thread's worth at a time, until the budget is spent or the image is
+    // done. At least one batch is rendered.
+    pub fn refine(&mut self, budget: Duration) {
+        let start = Instant::now();
+        let batch = rayon::current_num_threads();
+        while !self.is_done() {
+            let tiles = self.tiles();
+            let end = (self.tile + batch).min(tiles.len());
+            let rendered: Vec<_> = tiles[self.tile..end]
+                .par_iter()
+                .map(|&tile| (tile, self.render_tile(tile)))
+                .collect();
+            for ((x0, y0, w, h), values) in rendered {
+                for y in 0..h {
+                    let row = self.index(x0, y0 + y);
+                    self.values[row..row + w as usize]
+                        .copy_from_slice(&values[(y * w) as usize..((y + 1) * w) as usize]);
+                }
+            }
+            self.tile = end;
+            if self.tile == tiles.len() {
+                self.pass += 1;
+                self.tile = 0;
+            }
+            if start.elapsed() >= budget {
+                break;
+            }
+        }
+    }
+
+    // Colours the values through a cyclic palette, the inside of the set black
+    pub fn image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            match self.values[self.index(x, y)] {
+                Some(v) => {
+                    let color = self.palette.sample((v / BAND).rem_euclid(1.0));
+                    Rgb(color.map(|c| (c * 255.0).round() as u8))
+                }
+                None => Rgb([0; 3]),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formulas() {
+        let mandelbrot = Formula::Mandelbrot;
+        assert_eq!(mandelbrot.escape(DVec2::ZERO, 1000), None);
+        assert_eq!(mandelbrot.escape(DVec2::new(-1.0, 0.0), 1000), None);
+        assert!(mandelbrot.escape(DVec2::new(0.5, 0.0), 1000).is_some());
+        // the count changes smoothly between points escaping after different iterations
+        let counts: Vec<f32> = (0..100)
+            .map(|i| {
+                let p = DVec2::new(0.3 + i as f64 * 1e-3, 0.0);
+                mandelbrot.escape(p, 1000).unwrap()
+            })
+            .collect();
+        assert!(counts.windows(2).all(|w| (w[0] - w[1]).abs() < 1.0));
+        assert!(counts[0] - counts[99] > 5.0);
+
+        // the julia set of 0 is the unit circle
+        let julia = Formula::Julia(DVec2::ZERO);
+        assert_eq!(julia.escape(DVec2::new(0.0, 0.99), 1000), None);
+        assert!(julia.escape(DVec2::new(0.0, 1.01), 1000).is_some());
+
+        let ship = Formula::BurningShip;
+        assert_eq!(ship.escape(DVec2::new(-1.75, 0.0), 1000), None);
+        // unlike the mandelbrot set, it is not symmetric about the real axis
+        let (above, below) = (DVec2::new(-0.5, 0.8), DVec2::new(-0.5, -0.8));
+        assert_ne!(ship.escape(above, 1000), ship.escape(below, 1000));
+    }
+
+    #[test]
+    fn refinement() {
+        let mut escape = Escape::new(Formula::Mandelbrot, 150, 100);
+        // the first pass fills blocks with one sample each
+        while escape.pass == 0 {
+            escape.refine(Duration::ZERO);
+        }
+        let block = BLOCKS[0] as usize;
+        for y in 0..100 {
+            for x in 0..150 {
+                let corner = (y / block * block) * 150 + x / block * block;
+                assert_eq!(escape.values[y * 150 + x], escape.values[corner]);
+            }
+        }
+        // the last one samples every pixel
+        escape.refine(Duration::from_secs(60));
+        assert!(escape.is_done());
+        for y in 0..100 {
+            for x in 0..150 {
+                assert_eq!(escape.values[(y * 150 + x) as usize], escape.sample(x, y));
+            }
+        }
+        let image = escape.image();
+        assert_eq!(image.get_pixel(75, 50), &Rgb([0; 3]));
+        assert_ne!(image.get_pixel(0, 0), &Rgb([0; 3]));
+    }
+
+    #[test]
+    fn zoom() {
+        let mut escape = Escape::new(Formula::Mandelbrot, 200, 100);
+        assert!((escape.get_zoom() - 1.0).abs() < 1e-9);
+        let pos = Vec2::new(30.0, -20.0);
+        let p = escape.point(pos);
+        escape.zoom_at(pos, 8.0);
+        assert!((escape.point(pos) - p).length() < 1e-12);
+        assert!((escape.get_zoom() - 8.0).abs() < 1e-9);
+        // dragging moves the plane along with the mouse
+        escape.pan(Vec2::new(10.0, 0.0));
+        assert!((escape.point(pos + Vec2::new(10.0, 0.0)) - p).length() < 1e-12);
+        escape.reset();
+        assert_eq!(escape.point(Vec2::ZERO), Formula::Mandelbrot.home());
+    }
+
+    #[test]
+    fn limits() {
+        let mut escape = Escape::new(Formula::Mandelbrot, 200, 100);
+        escape.set_iterations(u32::MAX);
+        assert_eq!(escape.get_iterations(), MAX_ITERATIONS);
+        escape.set_iterations(0);
+        assert_eq!(escape.get_iterations(), 1);
+        escape.resize(0, 0);
+        assert_eq!(escape.image().dimensions(), (200, 100));
+        escape.reset();
+        assert!((escape.get_zoom() - 1.0).abs() < 1e-9);
+    }
+}
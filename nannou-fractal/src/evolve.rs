@@ -0,0 +1,338 @@
+use crate::koch::{Koch, KochSpec};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+const ATTEMPTS: usize = 20; // tries at a valid mutation or child before giving up
+const ANGLE_STEP: f32 = 10.0; // largest change of the angle by a mutation, in degrees
+
+// Breeds L-systems from a preset. Each generation keeps the favourites of the last one and
+// fills up with their mutated children.
+#[derive(Debug, Clone)]
+pub struct Evolver {
+    root: String, // name of the preset the candidates descend from
+    candidates: Vec<KochSpec>,
+    generation: usize,
+    rng: StdRng,
+}
+
+// Splits a successor into its modules, each a symbol with its arguments
+fn tokens(s: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut depth = 0; // of parentheses
+    for c in s.chars().filter(|c| !c.is_whitespace()) {
+        match (c, tokens.last_mut()) {
+            ('(', Some(token)) => {
+                depth += 1;
+                token.push(c);
+            }
+            (')', Some(token)) => {
+                depth -= 1;
+                token.push(c);
+            }
+            (_, Some(token)) if depth > 0 => token.push(c),
+            _ => tokens.push(c.to_string()),
+        }
+    }
+    tokens
+}
+
+// Whether every branch of a successor is closed, and closed after it is opened
+fn balanced(tokens: &[String]) -> bool {
+    let mut depth = 0;
+    for token in tokens {
+        match token.as_str() {
+            "[" => depth += 1,
+            "]" if depth == 0 => return false,
+            "]" => depth -= 1,
+            _ => (),
+        }
+    }
+    depth == 0
+}
+
+// The bracket closing the branch opened at i, or opening the one closed at i
+fn partner(tokens: &[String], i: usize) -> Option<usize> {
+    let (open, close) = ("[", "]");
+    let mut depth = 0;
+    if tokens[i] == open {
+        for (j, token) in tokens.iter().enumerate().skip(i) {
+            depth += (token == open) as i32 - (token == close) as i32;
+            if depth == 0 {
+                return Some(j);
+            }
+        }
+    } else if tokens[i] == close {
+        for (j, token) in tokens.iter().enumerate().take(i + 1).rev() {
+            depth += (token == close) as i32 - (token == open) as i32;
+            if depth == 0 {
+                return Some(j);
+            }
+        }
+    }
+    None
+}
+
+// Splits a production into what comes before "=>" and the modules of its successor
+fn split(production: &str) -> Option<(&str, Vec<String>)> {
+    if production.trim_start().starts_with('#') {
+        return None;
+    }
+    let (head, successor) = production.split_once("=>")?;
+    Some((head, tokens(successor)))
+}
+
+fn join(head: &str, tokens: &[String]) -> String {
+    format!("{}=> {}", head, tokens.concat())
+}
+
+// Whether spec parses. Mutants may grow much faster than their parents, so they are not
+// expanded here.
+fn is_valid(spec: &KochSpec) -> bool {
+    let spec = KochSpec {
+        iterations: 0,
+        ..spec.clone()
+    };
+    Koch::try_from_spec(&spec).is_ok()
+}
+
+// Inserts, deletes or swaps a module of one successor, or changes the angle
+pub fn mutate(spec: &KochSpec, rng: &mut impl Rng) -> KochSpec {
+    let rules: Vec<_> = spec
+        .productions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| split(p).map(|(head, tokens)| (i, head, tokens)))
+        .collect();
+    // modules to insert are the ones the successors already use, and turns
+    let mut pool: Vec<String> = rules
+        .iter()
+        .flat_map(|(_, _, tokens)| tokens.iter().cloned())
+        .filter(|t| t != "[" && t != "]")
+        .chain(["+".to_string(), "-".to_string()])
+        .collect();
+    pool.sort();
+    pool.dedup();
+
+    for _ in 0..ATTEMPTS {
+        let mut child = spec.clone();
+        let (i, head, tokens) = match rules.choose(rng) {
+            Some(rule) if rng.gen_bool(0.8) => rule,
+            _ => {
+                let angle = spec.angle + rng.gen_range(-ANGLE_STEP..ANGLE_STEP);
+                child.angle = (angle * 10.0).round() / 10.0;
+                if child != *spec {
+                    return child;
+                }
+                continue;
+            }
+        };
+        let mut tokens = tokens.clone();
+        let len = tokens.len();
+        match rng.gen_range(0..4) {
+            0 => {
+                let token = pool.choose(rng).unwrap().clone();
+                tokens.insert(rng.gen_range(0..=len), token);
+            }
+            1 => {
+                // wraps some modules in a new branch
+                let (a, b) = (rng.gen_range(0..=len), rng.gen_range(0..=len));
+                tokens.insert(a.max(b), "]".to_string());
+                tokens.insert(a.min(b), "[".to_string());
+            }
+            2 if len > 0 => {
+                // deleting a bracket unwraps its branch
+                let k = rng.gen_range(0..len);
+                match partner(&tokens, k) {
+                    Some(j) => {
+                        tokens.remove(k.max(j));
+                        tokens.remove(k.min(j));
+                    }
+                    None => {
+                        tokens.remove(k);
+                    }
+                }
+            }
+            3 if len > 1 => {
+                let k = rng.gen_range(0..len - 1);
+                tokens.swap(k, k + 1);
+            }
+            _ => continue,
+        }
+        if !balanced(&tokens) {
+            continue;
+        }
+        child.productions[*i] = join(head, &tokens);
+        if child != *spec && is_valid(&child) {
+            return child;
+        }
+    }
+    spec.clone()
+}
+
+// Crosses each successor of a with the successor b has for the same predecessor, joining a
+// prefix of one to a suffix of the other. The rest is taken from a, or either for the angle.
+pub fn breed(a: &KochSpec, b: &KochSpec, rng: &mut impl Rng) -> KochSpec {
+    let theirs: Vec<_> = b.productions.iter().filter_map(|p| split(p)).collect();
+    for _ in 0..ATTEMPTS {
+        let mut child = a.clone();
+        child.angle = if rng.gen() { a.angle } else { b.angle };
+        for production in child.productions.iter_mut() {
+            let (head, mine) = match split(production) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let other = theirs.iter().find(|(h, _)| h.trim() == head.trim());
+            if let Some((_, other)) = other {
+                let i = rng.gen_range(0..=mine.len());
+                let j = rng.gen_range(0..=other.len());
+                let tokens: Vec<_> = mine[..i].iter().chain(&other[j..]).cloned().collect();
+                if balanced(&tokens) {
+                    *production = join(head, &tokens);
+                }
+            }
+        }
+        if is_valid(&child) {
+            return child;
+        }
+    }
+    a.clone()
+}
+
+impl Evolver {
+    // Starts from spec and size - 1 of its mutants
+    pub fn new(spec: &KochSpec, size: usize, seed: u64) -> Self {
+        let mut evolver = Self {
+            root: spec.name.clone(),
+            candidates: vec![spec.clone()],
+            generation: 0,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        while evolver.candidates.len() < size {
+            let child = mutate(spec, &mut evolver.rng);
+            evolver.push(child);
+        }
+        evolver
+    }
+
+    pub fn candidates(&self) -> &[KochSpec] {
+        &self.candidates
+    }
+
+    // Lowers the iterations of a candidate, e.g. to what can be drawn in time
+    pub fn set_iterations(&mut self, i: usize, iterations: usize) {
+        self.candidates[i].iterations = iterations;
+    }
+
+    pub fn get_generation(&self) -> usize {
+        self.generation
+    }
+
+    fn push(&mut self, mut spec: KochSpec) {
+        spec.name = format!(
+            "{}_{}_{}",
+            self.root,
+            self.generation,
+            self.candidates.len()
+        );
+        self.candidates.push(spec);
+    }
+
+    // Keeps the candidates at the given indices and breeds the rest of the next generation
+    // from them, or from everyone if there are no favourites
+    pub fn next_generation(&mut self, favourites: &[usize]) {
+        let size = self.candidates.len();
+        let parents: Vec<KochSpec> = match favourites {
+            [] => self.candidates.clone(),
+            _ => favourites
+                .iter()
+                .map(|&i| self.candidates[i].clone())
+                .collect(),
+        };
+        self.generation += 1;
+        self.candidates = favourites
+            .iter()
+            .map(|&i| self.candidates[i].clone())
+            .collect();
+        while self.candidates.len() < size {
+            let a = parents.choose(&mut self.rng).unwrap();
+            let b = parents.choose(&mut self.rng).unwrap();
+            let child = breed(a, b, &mut self.rng);
+            let child = mutate(&child, &mut self.rng);
+            self.push(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::fixtures::builtin_spec;
+
+    #[test]
+    fn modules() {
+        assert_eq!(
+            tokens("F(l) [+A(l*0.7, 2)]"),
+            vec!["F(l)", "[", "+", "A(l*0.7,2)", "]"]
+        );
+        let branch = tokens("F[+F[-F]]F");
+        assert!(balanced(&branch));
+        assert!(!balanced(&tokens("F]F[")));
+        assert_eq!(partner(&branch, 1), Some(8));
+        assert_eq!(partner(&branch, 7), Some(4));
+        assert_eq!(partner(&branch, 0), None);
+        assert_eq!(
+            split("0 < 1 > 0 (0.5)=> 1F1"),
+            Some(("0 < 1 > 0 (0.5)", tokens("1F1")))
+        );
+        assert_eq!(split("#ignore: +-F"), None);
+    }
+
+    #[test]
+    fn mutation() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for name in ["tree_a", "tree_parametric", "tree_signal", "dragon"] {
+            let spec = builtin_spec(name);
+            for _ in 0..20 {
+                let child = mutate(&spec, &mut rng);
+                assert_ne!(child, spec);
+                assert!(is_valid(&child), "{:?}", child);
+            }
+        }
+    }
+
+    #[test]
+    fn breeding() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let (a, b) = (builtin_spec("tree_a"), builtin_spec("tree_c"));
+        for _ in 0..20 {
+            let child = breed(&a, &b, &mut rng);
+            assert!(is_valid(&child));
+            assert!([a.angle, b.angle].contains(&child.angle));
+            // the tree_a production only ever mixes with the other F production
+            let (_, successor) = split(&child.productions[0]).unwrap();
+            assert!(successor
+                .iter()
+                .all(|t| t.len() == 1 && "F+-[]".contains(t)));
+        }
+    }
+
+    #[test]
+    fn generations() {
+        let mut evolver = Evolver::new(&builtin_spec("tree_b"), 9, 3);
+        assert_eq!(evolver.candidates().len(), 9);
+        assert_eq!(evolver.candidates()[0], builtin_spec("tree_b"));
+        let favourites = [
+            evolver.candidates()[2].clone(),
+            evolver.candidates()[5].clone(),
+        ];
+        evolver.next_generation(&[2, 5]);
+        assert_eq!(evolver.get_generation(), 1);
+        assert_eq!(evolver.candidates().len(), 9);
+        assert_eq!(evolver.candidates()[..2], favourites);
+        let mut names: Vec<_> = evolver.candidates().iter().map(|s| &s.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 9);
+        assert!(evolver.candidates().iter().all(is_valid));
+    }
+}
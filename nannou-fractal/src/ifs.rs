@@ -0,0 +1,266 @@
+use crate::style::Style;
+
+use glam::{Mat2, Vec2};
+use nannou::image::{Rgb, RgbImage};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+const WARMUP: usize = 20; // points skipped before the chaos game settles on the attractor
+const PROBE: usize = 10_000; // points played to find the bounds of the attractor
+const MARGIN: f32 = 0.05; // share of the image left around the attractor
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct IfsSpec {
+    pub name: String,
+    pub maps: Vec<[f32; 6]>, // a, b, c, d, e, f mapping (x, y) to (ax + by + e, cx + dy + f)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probabilities: Vec<f32>, // chance of each map, by default its share of the area
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gradient: Vec<String>, // colours from sparse to dense, empty for the default
+}
+
+// An iterated function system: affine maps picked at random by the chaos game
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ifs {
+    name: String,
+    maps: Vec<(Mat2, Vec2)>,
+    cumulative: Vec<f32>, // running total of the probabilities, ending at 1
+}
+
+impl Ifs {
+    pub fn try_from_spec(spec: &IfsSpec) -> Result<Self, String> {
+        if spec.maps.is_empty() {
+            return Err("no maps".to_string());
+        }
+        let maps: Vec<_> = spec
+            .maps
+            .iter()
+            .map(|&[a, b, c, d, e, f]| {
+                (
+                    Mat2::from_cols(Vec2::new(a, c), Vec2::new(b, d)),
+                    Vec2::new(e, f),
+                )
+            })
+            .collect();
+        let weights = match spec.probabilities.len() {
+            0 => maps
+                .iter()
+                // maps that squash everything onto a line, like the stem of a fern, still
+                // get a few points
+                .map(|(m, _)| m.determinant().abs().max(0.01))
+                .collect(),
+            n if n == maps.len() => spec.probabilities.clone(),
+            n => return Err(format!("{} probabilities for {} maps", n, maps.len())),
+        };
+        if weights.iter().any(|&w| w < 0.0) || weights.iter().sum::<f32>() <= 0.0 {
+            return Err("probabilities must be positive".to_string());
+        }
+        let total: f32 = weights.iter().sum();
+        let cumulative = weights
+            .iter()
+            .scan(0.0, |sum, w| {
+                *sum += w / total;
+                Some(*sum)
+            })
+            .collect();
+        Ok(Self {
+            name: spec.name.clone(),
+            maps,
+            cumulative,
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // Applies a map picked with the probabilities of the maps
+    fn step(&self, p: Vec2, rng: &mut impl Rng) -> Vec2 {
+        let x: f32 = rng.gen();
+        let i = self.cumulative.iter().position(|&c| x < c);
+        let (m, t) = self.maps[i.unwrap_or(self.maps.len() - 1)];
+        m * p + t
+    }
+
+    // Points of the attractor, as played by the chaos game
+    pub fn points(&self, seed: u64) -> impl Iterator<Item = Vec2> + '_ {
+        let mut rng = StdRng::seed_from_u64(seed);
+        std::iter::successors(Some(Vec2::ZERO), move |&p| Some(self.step(p, &mut rng))).skip(WARMUP)
+    }
+}
+
+// How often the chaos game landed on each pixel of an image
+#[derive(Debug, Clone)]
+pub struct Density {
+    width: u32,
+    height: u32,
+    counts: Vec<u32>,
+    max: u32,
+    scale: f32,   // pixels per unit
+    center: Vec2, // of the attractor
+}
+
+impl Density {
+    // An empty image fitting the attractor of ifs
+    pub fn new(ifs: &Ifs, width: u32, height: u32) -> Self {
+        let (min, max) = ifs.points(0).take(PROBE).fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(p), max.max(p)),
+        );
+        let size = (max - min).max(Vec2::splat(f32::EPSILON));
+        let room = Vec2::new(width as f32, height as f32) * (1.0 - 2.0 * MARGIN);
+        Self {
+            width,
+            height,
+            counts: vec![0; width as usize * height as usize],
+            max: 0,
+            scale: (room / size).min_element(),
+            center: (min + max) / 2.0,
+        }
+    }
+
+    pub fn add(&mut self, points: impl IntoIterator<Item = Vec2>) {
+        let half = Vec2::new(self.width as f32, self.height as f32) / 2.0;
+        for p in points {
+            let q = (p - self.center) * self.scale;
+            let (x, y) = (half.x + q.x, half.y - q.y);
+            if x < 0.0 || y < 0.0 || x >= self.width as f32 || y >= self.height as f32 {
+                continue;
+            }
+            let count = &mut self.counts[y as usize * self.width as usize + x as usize];
+            *count += 1;
+            self.max = self.max.max(*count);
+        }
+    }
+
+    // Colours each pixel along the gradient by the log of its count, so that the sparse parts
+    // of the attractor still show next to the dense ones
+    pub fn image(&self, style: &Style) -> RgbImage {
+        let top = (self.max as f32).ln_1p().max(f32::EPSILON);
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let count = self.counts[y as usize * self.width as usize + x as usize];
+            if count == 0 {
+                return Rgb([0; 3]);
+            }
+            let color = style.sample((count as f32).ln_1p() / top);
+            Rgb(color.map(|c| (c * 255.0).round() as u8))
+        })
+    }
+}
+
+// A chaos game in progress
+#[derive(Debug, Clone)]
+pub struct ChaosGame {
+    pub ifs: Ifs,
+    pub density: Density,
+    pub style: Style,
+    rng: StdRng,
+    point: Vec2,
+    played: usize,
+}
+
+impl ChaosGame {
+    pub fn new(ifs: Ifs, style: Style, width: u32, height: u32, seed: u64) -> Self {
+        let density = Density::new(&ifs, width, height);
+        Self {
+            ifs,
+            density,
+            style,
+            rng: StdRng::seed_from_u64(seed),
+            point: Vec2::ZERO,
+            played: 0,
+        }
+    }
+
+    pub fn play(&mut self, n: usize) {
+        let mut points = Vec::with_capacity(n);
+        for _ in 0..n {
+            self.point = self.ifs.step(self.point, &mut self.rng);
+            self.played += 1;
+            if self.played > WARMUP {
+                points.push(self.point);
+            }
+        }
+        self.density.add(points);
+    }
+
+    pub fn get_played(&self) -> usize {
+        self.played
+    }
+
+    pub fn image(&self) -> RgbImage {
+        self.density.image(&self.style)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::fixtures::builtin_ifs;
+
+    #[test]
+    fn maps() {
+        let spec = IfsSpec {
+            name: "test".to_string(),
+            maps: vec![
+                [0.5, 0.0, 0.0, 0.5, 0.0, 0.0],
+                [0.0, -1.0, 1.0, 0.0, 2.0, 3.0],
+            ],
+            probabilities: Vec::new(),
+            gradient: Vec::new(),
+        };
+        let ifs = Ifs::try_from_spec(&spec).unwrap();
+        // the rotation covers four times the area of the shrinking map
+        assert_eq!(ifs.cumulative, vec![0.2, 1.0]);
+        let (m, t) = ifs.maps[1];
+        assert_eq!(m * Vec2::new(1.0, 0.0) + t, Vec2::new(2.0, 4.0));
+
+        let wrong = IfsSpec {
+            probabilities: vec![1.0],
+            ..spec.clone()
+        };
+        assert!(Ifs::try_from_spec(&wrong).is_err());
+        let negative = IfsSpec {
+            probabilities: vec![1.0, -1.0],
+            ..spec
+        };
+        assert!(Ifs::try_from_spec(&negative).is_err());
+    }
+
+    #[test]
+    fn attractors() {
+        // the chaos game never leaves the triangle
+        let sierpinski = builtin_ifs("sierpinski_chaos");
+        for p in sierpinski.points(1).take(10_000) {
+            let inside = p.y >= -1e-4 && p.y <= 3f32.sqrt() * p.x.min(1.0 - p.x) + 1e-4;
+            assert!(inside, "{}", p);
+        }
+        // nor the leaf of the fern
+        for p in builtin_ifs("fern").points(2).take(10_000) {
+            assert!(p.x.abs() < 3.0 && p.y >= 0.0 && p.y < 10.1, "{}", p);
+        }
+        for name in ["heighway", "maple"] {
+            assert!(builtin_ifs(name)
+                .points(3)
+                .take(1000)
+                .all(|p| p.is_finite()));
+        }
+    }
+
+    #[test]
+    fn density() {
+        let ifs = builtin_ifs("sierpinski_chaos");
+        let mut game = ChaosGame::new(ifs, Style::default(), 64, 64, 4);
+        game.play(20_000);
+        assert_eq!(game.get_played(), 20_000);
+        let image = game.image();
+        // the corners of the triangle are lit, the middle and the top corners are not
+        assert_ne!(image.get_pixel(10, 56), &Rgb([0; 3]));
+        assert_ne!(image.get_pixel(54, 56), &Rgb([0; 3]));
+        assert_eq!(image.get_pixel(32, 48), &Rgb([0; 3]));
+        assert_eq!(image.get_pixel(1, 1), &Rgb([0; 3]));
+        // the densest pixel takes the last colour of the gradient
+        let last = game.style.gradient[2].map(|c| (c * 255.0).round() as u8);
+        assert!(image.pixels().any(|p| p == &Rgb(last)));
+    }
+}
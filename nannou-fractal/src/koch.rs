@@ -1,13 +1,15 @@
 use crate::pest::Parser;
 
-use nannou::math::deg_to_rad;
+use pest::error::{Error, LineColLocation};
 use pest::iterators::Pair;
-use rand::{
-    distributions::{Distribution, Standard},
-    Rng,
-};
-use std::collections::HashMap;
-use std::f32::consts::{FRAC_PI_2, FRAC_PI_3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use symbols::{Symbol, SymbolTable, Word};
+
+pub mod interpreter;
+mod symbols;
 
 #[derive(Parser)]
 #[grammar = "grammar/koch.pest"]
@@ -16,361 +18,1032 @@ struct KochParser;
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum TurtleStep {
     Forward(char),
-    ForwardNoLine,
+    ForwardNoLine(char),
     TurnLeft,
     TurnRight,
+    PitchDown,
+    PitchUp,
+    RollLeft,
+    RollRight,
+    TurnAround,
+    Width, // ! narrows the line or sets its width
+    Color, // ' steps the colour index or sets it
+    BeginPolygon,
+    Vertex,
+    EndPolygon,
     Push,
     Pop,
     Reset,
     Node(char),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub step: TurtleStep,
+    pub params: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f32),
+    Param(usize), // index into the formal parameters of the production
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ModuleTemplate {
+    symbol: Symbol,
+    args: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Production {
+    weight: f32,
+    left: Vec<Symbol>,  // left context, empty if context-free
+    right: Vec<Symbol>, // right context, empty if context-free
+    successor: Vec<ModuleTemplate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Axiom,
+    Productions,
+}
+
+// Positions are 1-based; in the productions the line is the index of the production
+#[derive(Debug, Clone, PartialEq)]
+pub enum KochError {
+    Syntax {
+        section: Section,
+        line: usize,
+        col: usize,
+        message: String,
+    },
+    UnknownParameter {
+        section: Section,
+        line: usize,
+        col: usize,
+        name: String,
+    },
+    TooLarge {
+        n: usize,
+    },
+    TooManySymbols, // more distinct modules than a Symbol can number
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct KochSpec {
+    pub name: String,
+    pub axiom: String,
+    pub productions: Vec<String>,
+    pub iterations: usize,
+    pub angle: f32, // in degrees
+    #[serde(default = "KochSpec::default_draw")]
+    pub draw: String, // letters that draw a line
+    #[serde(default = "KochSpec::default_moves", rename = "move")]
+    pub moves: String, // letters that move without drawing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gradient: Vec<String>, // colours as #rrggbb, empty for the default
+}
+
+// Classifies the letters of an L-system. Letters that neither draw nor move are no-ops.
+#[derive(Debug, Clone, Copy)]
+struct Alphabet<'a> {
+    draw: &'a str,
+    moves: &'a str,
+}
+
+// Most modules a level may have, so that raising the iterations cannot exhaust the memory
+pub const MAX_MODULES: usize = 4_000_000;
+
+const DEFAULT_ALPHABET: Alphabet = Alphabet {
+    draw: "FLR",
+    moves: "f",
+};
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Koch {
     name: String,
-    initial_state: Vec<TurtleStep>,
-    rewrite_rules: HashMap<TurtleStep, Vec<TurtleStep>>,
+    symbols: SymbolTable,
+    initial_state: Word,
+    rewrite_rules: Vec<Vec<Production>>, // productions of each symbol
+    ignore: HashSet<TurtleStep>,         // symbols skipped when matching contexts
     n: usize,
-    state: Vec<TurtleStep>,
+    lazy: bool, // whether the levels past state are expanded on demand
+    state: Word,
     delta: f32,
-    step: usize, // current step in state
-}
-
-#[derive(Debug)]
-pub enum KochModel {
-    Cyclone,
-    Caret,
-    Islands,
-    Xshape,
-    Square,
-    Grid,
-    Sparse,
-    Dense,
-    Snowflake,
-    Dragon,
-    Sierpinski,
-    HexGosper,
-    QuadGosper,
-    TreeA,
-    TreeB,
-    TreeC,
-    TreeD,
-    TreeE,
-    TreeF,
-}
-
-impl Distribution<KochModel> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> KochModel {
-        match rng.gen_range(0..=18) {
-            0 => KochModel::Cyclone,
-            1 => KochModel::Caret,
-            2 => KochModel::Islands,
-            3 => KochModel::Xshape,
-            4 => KochModel::Square,
-            5 => KochModel::Grid,
-            6 => KochModel::Sparse,
-            7 => KochModel::Dense,
-            8 => KochModel::Snowflake,
-            9 => KochModel::Dragon,
-            10 => KochModel::Sierpinski,
-            11 => KochModel::HexGosper,
-            12 => KochModel::QuadGosper,
-            13 => KochModel::TreeA,
-            14 => KochModel::TreeB,
-            15 => KochModel::TreeC,
-            16 => KochModel::TreeD,
-            17 => KochModel::TreeE,
-            18 => KochModel::TreeF,
-            _ => unreachable!(),
-        }
-    }
-}
-
-fn parse_step(r: Pair<Rule>) -> TurtleStep {
+    seed: u64,      // seed for picking among stochastic productions
+    level: usize,   // number of iterations applied to state
+    cursor: Cursor, // position of next_step
+}
+
+// Position in a depth-first expansion of state down to level n. Only the successors on the
+// path to the current module are kept, so memory grows with n rather than with the output.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Cursor {
+    step: usize,                // next module in state
+    depth: usize,               // number of successors being expanded
+    frames: Vec<(Word, usize)>, // those successors with their next module, kept for reuse
+    rngs: Vec<StdRng>,          // one stream for each level still to expand, if stochastic
+}
+
+// The last rewrite of an L-system: each of the children was rewritten from the parent at
+// the same index in origins
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub parents: Vec<Module>,
+    pub children: Vec<Module>,
+    pub origins: Vec<usize>,
+}
+
+// Iterates over the modules of the final level without building it
+pub struct Modules<'a> {
+    koch: &'a Koch,
+    cursor: Cursor,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Section::Axiom => write!(f, "axiom"),
+            Section::Productions => write!(f, "productions"),
+        }
+    }
+}
+
+impl fmt::Display for KochError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KochError::Syntax {
+                section,
+                line,
+                col,
+                message,
+            } => write!(f, "{} {}:{}: {}", section, line, col, message),
+            KochError::UnknownParameter {
+                section,
+                line,
+                col,
+                name,
+            } => write!(
+                f,
+                "{} {}:{}: unknown parameter `{}`",
+                section, line, col, name
+            ),
+            KochError::TooLarge { n } => write!(
+                f,
+                "level {} would have more than the {} modules that can be drawn",
+                n, MAX_MODULES
+            ),
+            KochError::TooManySymbols => {
+                write!(f, "more than {} distinct modules", Symbol::MAX as usize + 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KochError {}
+
+impl KochError {
+    fn syntax(section: Section, e: Error<Rule>) -> Self {
+        let (line, col) = match e.line_col {
+            LineColLocation::Pos(pos) => pos,
+            LineColLocation::Span(start, _) => start,
+        };
+        KochError::Syntax {
+            section,
+            line,
+            col,
+            message: e.variant.message().to_string(),
+        }
+    }
+}
+
+impl KochSpec {
+    fn default_draw() -> String {
+        DEFAULT_ALPHABET.draw.to_string()
+    }
+
+    fn default_moves() -> String {
+        DEFAULT_ALPHABET.moves.to_string()
+    }
+}
+
+impl Default for KochSpec {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            axiom: String::new(),
+            productions: Vec::new(),
+            iterations: 0,
+            angle: 90.0,
+            draw: Self::default_draw(),
+            moves: Self::default_moves(),
+            gradient: Vec::new(),
+        }
+    }
+}
+
+impl Alphabet<'_> {
+    fn step(&self, c: char) -> TurtleStep {
+        if self.draw.contains(c) {
+            TurtleStep::Forward(c)
+        } else if self.moves.contains(c) {
+            TurtleStep::ForwardNoLine(c)
+        } else {
+            TurtleStep::Node(c)
+        }
+    }
+}
+
+impl From<TurtleStep> for Module {
+    fn from(step: TurtleStep) -> Self {
+        Module {
+            step,
+            params: Vec::new(),
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, params: &[f32]) -> f32 {
+        match self {
+            Expr::Number(x) => *x,
+            Expr::Param(i) => params[*i],
+            Expr::Neg(e) => -e.eval(params),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(params), b.eval(params));
+                match op {
+                    Op::Add => a + b,
+                    Op::Subtract => a - b,
+                    Op::Multiply => a * b,
+                    Op::Divide => a / b,
+                    Op::Power => a.powf(b),
+                }
+            }
+        }
+    }
+}
+
+impl ModuleTemplate {
+    fn instantiate(&self, params: &[f32], word: &mut Word) {
+        word.push(self.symbol, self.args.iter().map(|e| e.eval(params)));
+    }
+}
+
+// Walks left from i to find the modules matching the left context. Completed branches are
+// skipped and an enclosing push is stepped over, so the context is the path to the root.
+fn match_left(
+    patterns: &[Symbol],
+    state: &Word,
+    i: usize,
+    symbols: &SymbolTable,
+    ignore: &HashSet<TurtleStep>,
+) -> Option<Vec<usize>> {
+    let mut matched = Vec::with_capacity(patterns.len());
+    let mut j = i;
+    for &pattern in patterns.iter().rev() {
+        loop {
+            j = j.checked_sub(1)?;
+            match symbols.step(state.symbol(j)) {
+                TurtleStep::Pop => {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        j = j.checked_sub(1)?;
+                        match symbols.step(state.symbol(j)) {
+                            TurtleStep::Pop => depth += 1,
+                            TurtleStep::Push => depth -= 1,
+                            _ => (),
+                        }
+                    }
+                }
+                TurtleStep::Push => (),
+                step if ignore.contains(step) => (),
+                _ => break,
+            }
+        }
+        if state.symbol(j) != pattern {
+            return None;
+        }
+        matched.push(j);
+    }
+    matched.reverse();
+    Some(matched)
+}
+
+// Walks right from i to find the modules matching the right context. Lateral branches are
+// skipped and the end of the current branch stops the search.
+fn match_right(
+    patterns: &[Symbol],
+    state: &Word,
+    i: usize,
+    symbols: &SymbolTable,
+    ignore: &HashSet<TurtleStep>,
+) -> Option<Vec<usize>> {
+    let mut matched = Vec::with_capacity(patterns.len());
+    let mut j = i;
+    for &pattern in patterns {
+        loop {
+            j += 1;
+            if j >= state.len() {
+                return None;
+            }
+            match symbols.step(state.symbol(j)) {
+                TurtleStep::Push => {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        j += 1;
+                        if j >= state.len() {
+                            return None;
+                        }
+                        match symbols.step(state.symbol(j)) {
+                            TurtleStep::Push => depth += 1,
+                            TurtleStep::Pop => depth -= 1,
+                            _ => (),
+                        }
+                    }
+                }
+                TurtleStep::Pop => return None,
+                step if ignore.contains(step) => (),
+                _ => break,
+            }
+        }
+        if state.symbol(j) != pattern {
+            return None;
+        }
+        matched.push(j);
+    }
+    Some(matched)
+}
+
+impl Production {
+    fn is_context_free(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    // Returns the actual parameters for the formal ones if the production applies at i.
+    // The predecessor is known to match, as productions are looked up by its symbol.
+    fn bind(
+        &self,
+        state: &Word,
+        i: usize,
+        symbols: &SymbolTable,
+        ignore: &HashSet<TurtleStep>,
+    ) -> Option<Vec<f32>> {
+        if self.is_context_free() {
+            return Some(state.params(i).to_vec());
+        }
+        let left = match_left(&self.left, state, i, symbols, ignore)?;
+        let right = match_right(&self.right, state, i, symbols, ignore)?;
+        let mut params = Vec::new();
+        for j in left {
+            params.extend_from_slice(state.params(j));
+        }
+        params.extend_from_slice(state.params(i));
+        for j in right {
+            params.extend_from_slice(state.params(j));
+        }
+        Some(params)
+    }
+}
+
+fn parse_step(r: Pair<Rule>, alphabet: Alphabet) -> TurtleStep {
     match r.as_rule() {
-        Rule::forward => TurtleStep::Forward(r.as_str().chars().next().unwrap()),
-        Rule::forward_no_line => TurtleStep::ForwardNoLine,
+        Rule::letter => alphabet.step(r.as_str().chars().next().unwrap()),
         Rule::turn_left => TurtleStep::TurnLeft,
         Rule::turn_right => TurtleStep::TurnRight,
+        Rule::pitch_down => TurtleStep::PitchDown,
+        Rule::pitch_up => TurtleStep::PitchUp,
+        Rule::roll_left => TurtleStep::RollLeft,
+        Rule::roll_right => TurtleStep::RollRight,
+        Rule::turn_around => TurtleStep::TurnAround,
+        Rule::width => TurtleStep::Width,
+        Rule::color => TurtleStep::Color,
+        Rule::begin_polygon => TurtleStep::BeginPolygon,
+        Rule::vertex => TurtleStep::Vertex,
+        Rule::end_polygon => TurtleStep::EndPolygon,
         Rule::push => TurtleStep::Push,
         Rule::pop => TurtleStep::Pop,
-        Rule::node => TurtleStep::Node(r.as_str().chars().next().unwrap()),
-        _ => panic!("unexpected rule: {:?}", r.as_rule()),
+        _ => unreachable!("unexpected rule: {:?}", r.as_rule()),
     }
 }
 
-fn parse_steps(s: &str) -> Vec<TurtleStep> {
-    let state = KochParser::parse(Rule::state, s)
-        .expect("unsuccessful parse") // unwrap the parse result
+fn parse_binary(r: Pair<Rule>, names: &[String], section: Section) -> Result<Expr, KochError> {
+    let mut inner = r.into_inner();
+    let mut lhs = parse_expr(inner.next().unwrap(), names, section)?;
+    while let Some(op) = inner.next() {
+        let op = match op.as_rule() {
+            Rule::add => Op::Add,
+            Rule::subtract => Op::Subtract,
+            Rule::multiply => Op::Multiply,
+            Rule::divide => Op::Divide,
+            Rule::power => Op::Power,
+            _ => unreachable!("unexpected rule: {:?}", op.as_rule()),
+        };
+        let rhs = parse_expr(inner.next().unwrap(), names, section)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+// Turns an expression into a tree, resolving identifiers against the formal parameters
+fn parse_expr(r: Pair<Rule>, names: &[String], section: Section) -> Result<Expr, KochError> {
+    match r.as_rule() {
+        Rule::expr | Rule::term | Rule::factor => parse_binary(r, names, section),
+        Rule::atom => {
+            let mut inner = r.into_inner();
+            let first = inner.next().unwrap();
+            match first.as_rule() {
+                Rule::neg => {
+                    let e = parse_expr(inner.next().unwrap(), names, section)?;
+                    Ok(Expr::Neg(Box::new(e)))
+                }
+                _ => parse_expr(first, names, section),
+            }
+        }
+        Rule::number => Ok(Expr::Number(r.as_str().parse().unwrap())),
+        Rule::ident => match names.iter().position(|n| n == r.as_str()) {
+            Some(i) => Ok(Expr::Param(i)),
+            None => {
+                let (line, col) = r.as_span().start_pos().line_col();
+                Err(KochError::UnknownParameter {
+                    section,
+                    line,
+                    col,
+                    name: r.as_str().to_string(),
+                })
+            }
+        },
+        _ => unreachable!("unexpected rule: {:?}", r.as_rule()),
+    }
+}
+
+fn parse_pattern(
+    r: Pair<Rule>,
+    names: &mut Vec<String>,
+    symbols: &mut SymbolTable,
+    alphabet: Alphabet,
+) -> Result<Symbol, KochError> {
+    let mut inner = r.into_inner();
+    let step = parse_step(inner.next().unwrap(), alphabet);
+    let mut arity = 0;
+    if let Some(params) = inner.next() {
+        for p in params.into_inner() {
+            names.push(p.as_str().to_string());
+            arity += 1;
+        }
+    }
+    symbols.intern(&step, arity)
+}
+
+fn parse_module(
+    r: Pair<Rule>,
+    names: &[String],
+    section: Section,
+    symbols: &mut SymbolTable,
+    alphabet: Alphabet,
+) -> Result<ModuleTemplate, KochError> {
+    let mut inner = r.into_inner();
+    let step = parse_step(inner.next().unwrap(), alphabet);
+    let args: Vec<Expr> = match inner.next() {
+        Some(args) => args
+            .into_inner()
+            .map(|e| parse_expr(e, names, section))
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    Ok(ModuleTemplate {
+        symbol: symbols.intern(&step, args.len())?,
+        args,
+    })
+}
+
+fn parse_steps(s: &str, symbols: &mut SymbolTable, alphabet: Alphabet) -> Result<Word, KochError> {
+    let state = KochParser::parse(Rule::axiom, s)
+        .map_err(|e| KochError::syntax(Section::Axiom, e))?
+        .next()
+        .unwrap()
+        .into_inner()
         .next()
         .unwrap();
 
-    state.into_inner().map(parse_step).collect()
+    let mut word = Word::default();
+    for r in state.into_inner() {
+        parse_module(r, &[], Section::Axiom, symbols, alphabet)?.instantiate(&[], &mut word);
+    }
+    Ok(word)
 }
 
-fn parse_rewrite_rules(s: &str) -> HashMap<TurtleStep, Vec<TurtleStep>> {
+type RewriteRules = (HashMap<Symbol, Vec<Production>>, HashSet<TurtleStep>);
+
+fn parse_rewrite_rules(
+    s: &str,
+    symbols: &mut SymbolTable,
+    alphabet: Alphabet,
+) -> Result<RewriteRules, KochError> {
     let productions = KochParser::parse(Rule::productions, s)
-        .expect("unsuccessful parse") // unwrap the parse result
+        .map_err(|e| KochError::syntax(Section::Productions, e))?
         .next()
         .unwrap()
         .into_inner();
 
-    let mut rules = HashMap::new();
-    let mut from = TurtleStep::Forward('F');
+    let mut rules: HashMap<Symbol, Vec<Production>> = HashMap::new();
+    let mut ignore = HashSet::new();
     for r in productions {
         match r.as_rule() {
             Rule::production => {
+                let mut names = Vec::new();
+                let mut left = Vec::new();
+                let mut predecessor = None;
+                let mut right = Vec::new();
+                let mut weight = 1.0;
                 for ir in r.into_inner() {
                     match ir.as_rule() {
-                        Rule::predecessor => from = parse_step(ir.into_inner().next().unwrap()),
+                        Rule::left_context => {
+                            for p in ir.into_inner() {
+                                left.push(parse_pattern(p, &mut names, symbols, alphabet)?);
+                            }
+                        }
+                        Rule::predecessor => {
+                            let p = ir.into_inner().next().unwrap();
+                            predecessor = Some(parse_pattern(p, &mut names, symbols, alphabet)?);
+                        }
+                        Rule::right_context => {
+                            for p in ir.into_inner() {
+                                right.push(parse_pattern(p, &mut names, symbols, alphabet)?);
+                            }
+                        }
+                        Rule::probability => {
+                            weight = ir.into_inner().next().unwrap().as_str().parse().unwrap();
+                        }
                         Rule::state => {
-                            let to = ir.into_inner().map(parse_step).collect();
-                            rules.insert(from.clone(), to);
+                            let successor = ir
+                                .into_inner()
+                                .map(|m| {
+                                    parse_module(m, &names, Section::Productions, symbols, alphabet)
+                                })
+                                .collect::<Result<_, _>>()?;
+                            let predecessor = predecessor.take().unwrap();
+                            rules.entry(predecessor).or_default().push(Production {
+                                weight,
+                                left: std::mem::take(&mut left),
+                                right: std::mem::take(&mut right),
+                                successor,
+                            });
                         }
-                        _ => panic!("unexpected rule: {:?}", ir.as_rule()),
+                        _ => unreachable!("unexpected rule: {:?}", ir.as_rule()),
                     }
                 }
             }
+            Rule::ignore => ignore.extend(r.into_inner().map(|r| parse_step(r, alphabet))),
             Rule::EOI => (),
-            _ => panic!("unexpected rule: {:?}", r.as_rule()),
+            _ => unreachable!("unexpected rule: {:?}", r.as_rule()),
         }
     }
-    rules
+    Ok((rules, ignore))
 }
 
-impl Koch {
-    pub fn get_name(&self) -> &str {
-        &self.name
+// Picks the production to apply at i. Context-sensitive productions take precedence over
+// context-free ones, and the weights decide among the remaining candidates.
+fn choose<'a, R: Rng>(
+    productions: &'a [Production],
+    state: &Word,
+    i: usize,
+    symbols: &SymbolTable,
+    ignore: &HashSet<TurtleStep>,
+    rng: Option<&mut R>,
+) -> Option<(&'a Production, Vec<f32>)> {
+    let bind = |p: &'a Production| p.bind(state, i, symbols, ignore).map(|params| (p, params));
+    match productions {
+        [] => return None,
+        [p] => return bind(p),
+        _ => (),
     }
-
-    pub fn get_delta(&self) -> f32 {
-        self.delta
+    let mut candidates: Vec<_> = productions.iter().filter_map(bind).collect();
+    if candidates.iter().any(|(p, _)| !p.is_context_free()) {
+        candidates.retain(|(p, _)| !p.is_context_free());
     }
-
-    pub fn model(m: KochModel) -> Koch {
-        match m {
-            KochModel::Cyclone => Self::cyclone(),
-            KochModel::Caret => Self::caret(),
-            KochModel::Islands => Self::islands(),
-            KochModel::Xshape => Self::xshape(),
-            KochModel::Square => Self::square(),
-            KochModel::Grid => Self::grid(),
-            KochModel::Sparse => Self::sparse(),
-            KochModel::Dense => Self::dense(),
-            KochModel::Snowflake => Self::snowflake(),
-            KochModel::Dragon => Self::dragon(),
-            KochModel::Sierpinski => Self::sierpinski(),
-            KochModel::HexGosper => Self::hex_gosper(),
-            KochModel::QuadGosper => Self::quad_gosper(),
-            KochModel::TreeA => Self::tree_a(),
-            KochModel::TreeB => Self::tree_b(),
-            KochModel::TreeC => Self::tree_c(),
-            KochModel::TreeD => Self::tree_d(),
-            KochModel::TreeE => Self::tree_e(),
-            KochModel::TreeF => Self::tree_f(),
-        }
-    }
-
-    fn cyclone() -> Self {
-        Self::from("cyclone", "F-F-F-F", "F => F-F+F+FF-F-F+F", 2, FRAC_PI_2)
-    }
-
-    fn caret() -> Self {
-        Self::from("caret", "-F", "F => F+F-F-F+F", 4, FRAC_PI_2)
-    }
-
-    fn islands() -> Self {
-        Self::from(
-            "islands",
-            "F+F+F+F",
-            "F => F+f-FF+F+FF+Ff+FF-f+FF-F-FF-Ff-FFF\nf => ffffff",
-            2,
-            FRAC_PI_2,
-        )
-    }
-
-    fn xshape() -> Self {
-        Self::from("xshape", "F-F-F-F", "F => FF-F-F-F-F-F+F", 4, FRAC_PI_2)
-    }
-
-    fn square() -> Self {
-        Self::from("square", "F-F-F-F", "F => FF-F-F-F-FF", 4, FRAC_PI_2)
+    if candidates.len() <= 1 {
+        return candidates.pop();
     }
-
-    fn grid() -> Self {
-        Self::from("grid", "F-F-F-F", "F => FF-F+F-F-FF", 3, FRAC_PI_2)
+    let total: f32 = candidates.iter().map(|(p, _)| p.weight).sum();
+    // without a stream, as for deterministic systems, the last candidate is picked
+    let mut x = rng.map_or(total, |rng| rng.gen::<f32>() * total);
+    let mut chosen = candidates.len() - 1;
+    for (k, (p, _)) in candidates.iter().enumerate() {
+        if x < p.weight {
+            chosen = k;
+            break;
+        }
+        x -= p.weight;
     }
+    Some(candidates.swap_remove(chosen))
+}
 
-    fn sparse() -> Self {
-        Self::from("sparse", "F-F-F-F", "F => FF-F--F-F", 4, FRAC_PI_2)
-    }
+// Stream for picking among the productions of one level. Each level gets its own so that a
+// given seed always yields the same plant, and seed and level are mixed with SplitMix64 so that
+// neighbouring seeds do not share streams at neighbouring levels.
+fn level_rng(seed: u64, level: usize) -> StdRng {
+    let mut z =
+        (seed ^ (level as u64).wrapping_mul(0x9E3779B97F4A7C15)).wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    StdRng::seed_from_u64(z ^ (z >> 31))
+}
 
-    fn dense() -> Self {
-        Self::from("dense", "F-F-F-F", "F => F-FF--F-F", 5, FRAC_PI_2)
+impl Cursor {
+    // At the first module of koch. Streams are only made when there are productions to pick
+    // among.
+    fn new(koch: &Koch) -> Self {
+        let levels = koch.level..koch.n.max(koch.level);
+        let rngs = if koch.is_stochastic() {
+            levels.map(|level| level_rng(koch.seed, level)).collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            step: 0,
+            depth: 0,
+            frames: Vec::new(),
+            rngs,
+        }
     }
+}
 
-    fn snowflake() -> Self {
-        Self::from("snowflake", "F-F-F-F", "F => F-F+F-F-F", 4, FRAC_PI_2)
-    }
+impl Iterator for Modules<'_> {
+    type Item = Module;
 
-    fn dragon() -> Self {
-        Self::from("dragon", "L", "L => L+R+\nR => -L-R", 10, FRAC_PI_2)
+    fn next(&mut self) -> Option<Module> {
+        self.koch.advance(&mut self.cursor)
     }
+}
 
-    fn sierpinski() -> Self {
-        Self::from("sierpinski", "R", "L => R+L+R\nR => L-R-L", 6, FRAC_PI_3)
+impl Koch {
+    pub fn get_name(&self) -> &str {
+        &self.name
     }
 
-    fn hex_gosper() -> Self {
-        Self::from(
-            "hex_gosper",
-            "L",
-            "L => L+R++R-L--LL-R+\nR => -L+RR++R+L--L-R",
-            4,
-            FRAC_PI_3,
-        )
+    pub fn get_delta(&self) -> f32 {
+        self.delta
     }
 
-    fn quad_gosper() -> Self {
-        Self::from("quad_gosper","-R", "L => LL-R-R+L+L-R-RL+R+LLR-L+R+LL+R-LR-R-L+L+RR-\nR => +LL-R-R+L+LR+L-RR-L-R+LRR-L-RL+L+R-R-L+L+RR", 2, FRAC_PI_2)
+    pub fn set_delta(&mut self, delta: f32) {
+        self.delta = delta;
     }
 
-    fn tree_a() -> Self {
-        Self::from("tree_a", "F", "F => F[+F]F[-F]F", 5, deg_to_rad(25.7))
+    pub fn get_n(&self) -> usize {
+        self.n
     }
 
-    fn tree_b() -> Self {
-        Self::from("tree_b", "F", "F => F[+F]F[-F][F]", 5, deg_to_rad(20.0))
+    pub fn set_n(&mut self, n: usize) {
+        self.n = n;
+        if n < self.level {
+            self.regenerate();
+        } else {
+            self.expand();
+        }
+        self.rewind();
     }
 
-    fn tree_c() -> Self {
-        Self::from(
-            "tree_c",
-            "F",
-            "F => FF-[-F+F+F]+[+F-F-F]",
-            5,
-            deg_to_rad(22.5),
-        )
+    // Expected number of modules at level n, counting what each module is rewritten into on
+    // average over its productions. Stochastic and context-sensitive systems may come out
+    // larger or smaller. The count stops at the first level past MAX_MODULES.
+    pub fn estimate_len(&self, n: usize) -> f64 {
+        let size = self.symbols.len();
+        let yields: Vec<Vec<(Symbol, f64)>> = self
+            .rewrite_rules
+            .iter()
+            .enumerate()
+            .map(|(symbol, productions)| {
+                let total: f32 = productions.iter().map(|p| p.weight).sum();
+                if productions.is_empty() || total <= 0.0 {
+                    return vec![(symbol as Symbol, 1.0)];
+                }
+                productions
+                    .iter()
+                    .flat_map(|p| {
+                        let share = (p.weight / total) as f64;
+                        p.successor.iter().map(move |t| (t.symbol, share))
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut counts = vec![0.0; size];
+        for i in 0..self.initial_state.len() {
+            counts[self.initial_state.symbol(i) as usize] += 1.0;
+        }
+        for _ in 0..n {
+            let mut next = vec![0.0; size];
+            for (symbol, &count) in counts.iter().enumerate() {
+                for &(t, share) in yields[symbol].iter() {
+                    next[t as usize] += count * share;
+                }
+            }
+            counts = next;
+            if counts.iter().sum::<f64>() > MAX_MODULES as f64 {
+                break;
+            }
+        }
+        counts.iter().sum()
     }
 
-    fn tree_d() -> Self {
-        Self::from(
-            "tree_d",
-            "X",
-            "X => F[+X]F[-X]+X\nF => FF",
-            7,
-            deg_to_rad(20.0),
-        )
+    // Whether level n is small enough to draw
+    pub fn check_n(&self, n: usize) -> Result<(), KochError> {
+        if self.estimate_len(n) > MAX_MODULES as f64 {
+            return Err(KochError::TooLarge { n });
+        }
+        Ok(())
     }
 
-    fn tree_e() -> Self {
-        Self::from(
-            "tree_e",
-            "X",
-            "X => F[+X][-X]FX\nF => FF",
-            7,
-            deg_to_rad(25.7),
-        )
+    pub fn modules(&self) -> Modules<'_> {
+        Modules {
+            koch: self,
+            cursor: Cursor::new(self),
+        }
     }
 
-    fn tree_f() -> Self {
-        Self::from(
-            "tree_f",
-            "X",
-            "X => F-[[X]+X]+F[+FX]-X\nF => FF",
-            5,
-            deg_to_rad(22.5),
+    pub fn try_from_spec(spec: &KochSpec) -> Result<Self, KochError> {
+        let alphabet = Alphabet {
+            draw: &spec.draw,
+            moves: &spec.moves,
+        };
+        Self::parse(
+            &spec.name,
+            &spec.axiom,
+            &spec.productions.join("\n"),
+            alphabet,
+            spec.iterations,
+            spec.angle.to_radians(),
         )
     }
 
-    fn from(name: &str, initial_state: &str, rewrite_rules: &str, n: usize, delta: f32) -> Self {
-        let initial_state = parse_steps(initial_state);
-        let rewrite_rules = parse_rewrite_rules(rewrite_rules);
+    fn parse(
+        name: &str,
+        initial_state: &str,
+        rewrite_rules: &str,
+        alphabet: Alphabet,
+        n: usize,
+        delta: f32,
+    ) -> Result<Self, KochError> {
+        let mut symbols = SymbolTable::default();
+        let initial_state = parse_steps(initial_state, &mut symbols, alphabet)?;
+        let (mut rules, ignore) = parse_rewrite_rules(rewrite_rules, &mut symbols, alphabet)?;
+        let rewrite_rules: Vec<Vec<Production>> = (0..symbols.len())
+            .map(|symbol| rules.remove(&(symbol as Symbol)).unwrap_or_default())
+            .collect();
+        let lazy = rewrite_rules
+            .iter()
+            .flatten()
+            .all(Production::is_context_free);
         let state = initial_state.clone();
         let mut koch = Koch {
             name: name.to_string(),
+            symbols,
             initial_state,
             rewrite_rules,
+            ignore,
             state,
             n,
+            lazy,
             delta,
-            step: 0,
+            seed: 0,
+            level: 0,
+            cursor: Cursor::default(),
         };
-        for _ in 0..n {
-            koch.next_iteration();
+        koch.check_n(n)?;
+        koch.expand();
+        koch.rewind();
+        Ok(koch)
+    }
+
+    // Whether some module has several productions to pick among
+    fn is_stochastic(&self) -> bool {
+        self.rewrite_rules
+            .iter()
+            .any(|productions| productions.len() > 1)
+    }
+
+    // Restarts next_step from the first module
+    pub fn rewind(&mut self) {
+        self.cursor = Cursor::new(self);
+    }
+
+    pub fn next_step(&mut self) -> Module {
+        let mut cursor = std::mem::take(&mut self.cursor);
+        let module = self.advance(&mut cursor);
+        self.cursor = cursor;
+        module.unwrap_or_else(|| {
+            self.rewind();
+            TurtleStep::Reset.into()
+        })
+    }
+
+    // Yields the next module of level n, rewriting the modules above it on the way down
+    fn advance(&self, cursor: &mut Cursor) -> Option<Module> {
+        loop {
+            let depth = cursor.depth;
+            let (word, i) = match depth.checked_sub(1) {
+                Some(top) => {
+                    let (word, i) = &mut cursor.frames[top];
+                    if *i == word.len() {
+                        cursor.depth -= 1;
+                        continue;
+                    }
+                    *i += 1;
+                    (&*word, *i - 1)
+                }
+                None => {
+                    if cursor.step == self.state.len() {
+                        return None;
+                    }
+                    cursor.step += 1;
+                    (&self.state, cursor.step - 1)
+                }
+            };
+            let symbol = word.symbol(i);
+            if self.level + depth >= self.n {
+                return Some(self.symbols.module(symbol, word.params(i)));
+            }
+            // modules are visited in order within each level, so the streams are drawn from
+            // in the same order as next_iteration does
+            let productions = &self.rewrite_rules[symbol as usize];
+            let rng = cursor.rngs.get_mut(depth);
+            let (p, params) = match choose(productions, word, i, &self.symbols, &self.ignore, rng) {
+                Some(production) => production,
+                // a module no production applies to stays the same in the levels below
+                None => return Some(self.symbols.module(symbol, word.params(i))),
+            };
+            if cursor.frames.len() == depth {
+                cursor.frames.push(Default::default());
+            }
+            let (successor, next) = &mut cursor.frames[depth];
+            successor.clear();
+            *next = 0;
+            for t in p.successor.iter() {
+                t.instantiate(&params, successor);
+            }
+            cursor.depth += 1;
         }
-        koch
     }
 
-    pub fn next_step(&mut self) -> TurtleStep {
-        if self.step >= self.state.len() {
-            self.step = 0;
-            return TurtleStep::Reset;
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.regenerate();
+    }
+
+    // Rebuilds the state from the axiom, as the iterations depend on each other
+    fn regenerate(&mut self) {
+        self.state = self.initial_state.clone();
+        self.level = 0;
+        self.expand();
+        self.rewind();
+    }
+
+    // Context-sensitive productions look at the neighbours of a module, so those systems
+    // are rewritten a whole level at a time instead of on demand
+    fn expand(&mut self) {
+        if !self.lazy {
+            while self.level < self.n {
+                self.next_iteration();
+            }
         }
-        let s = self.state[self.step].clone();
-        self.step += 1;
-        s
     }
 
     pub fn next_iteration(&mut self) {
-        let mut new_state = Vec::new();
-        for step in &self.state {
-            if let Some(rule) = self.rewrite_rules.get(&step) {
-                new_state.extend(rule.iter().cloned());
-            } else {
-                new_state.push(step.clone());
+        self.state = self.rewrite(None);
+        self.level += 1;
+        self.rewind();
+    }
+
+    // Rewrites every module of state once, noting where each new module came from if asked
+    fn rewrite(&self, mut origins: Option<&mut Vec<usize>>) -> Word {
+        let mut rng = level_rng(self.seed, self.level);
+        let mut new_state = Word::default();
+        for i in 0..self.state.len() {
+            let symbol = self.state.symbol(i);
+            let productions = &self.rewrite_rules[symbol as usize];
+            match choose(
+                productions,
+                &self.state,
+                i,
+                &self.symbols,
+                &self.ignore,
+                Some(&mut rng),
+            ) {
+                Some((p, params)) => {
+                    for t in p.successor.iter() {
+                        t.instantiate(&params, &mut new_state);
+                    }
+                }
+                None => new_state.push(symbol, self.state.params(i).iter().copied()),
+            }
+            if let Some(origins) = origins.as_deref_mut() {
+                origins.resize(new_state.len(), i);
             }
         }
-        self.state = new_state;
-        self.step = 0;
+        new_state
+    }
+
+    // Levels n - 1 and n side by side, or None at the axiom
+    pub fn generation(&self) -> Option<Generation> {
+        let mut koch = self.clone();
+        koch.n = self.n.checked_sub(1)?;
+        koch.lazy = false;
+        koch.regenerate();
+        let mut origins = Vec::new();
+        let children = koch.rewrite(Some(&mut origins));
+        Some(Generation {
+            parents: koch.symbols.decode(&koch.state),
+            children: koch.symbols.decode(&children),
+            origins,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::preset;
+    use crate::preset::fixtures::{builtin_koch, builtin_spec, spec};
+    use std::f32::consts::FRAC_PI_2;
+
+    fn dragon_len(n: usize) -> usize {
+        let mut dragon = builtin_koch("dragon");
+        dragon.set_n(n);
+        dragon.modules().count()
+    }
+
+    fn symbols(state: &[Module]) -> Vec<TurtleStep> {
+        state.iter().map(|m| m.step.clone()).collect()
+    }
+
+    fn steps(s: &str) -> Vec<Module> {
+        let mut table = SymbolTable::default();
+        let word = parse_steps(s, &mut table, DEFAULT_ALPHABET).unwrap();
+        table.decode(&word)
+    }
+
+    fn state(koch: &Koch) -> Vec<Module> {
+        koch.symbols.decode(&koch.state)
+    }
 
     #[test]
     fn constructor() {
-        let koch = super::Koch::from("test", "F", "F => F+F-", 0, FRAC_PI_2);
-        assert_eq!(koch.initial_state, vec![TurtleStep::Forward('F')]);
+        let koch = Koch::parse("test", "F", "F => F+F-", DEFAULT_ALPHABET, 0, FRAC_PI_2).unwrap();
+        assert_eq!(symbols(&state(&koch)), vec![TurtleStep::Forward('F')]);
+        assert_eq!(koch.symbols.len(), 3);
+        let productions = &koch.rewrite_rules[koch.state.symbol(0) as usize];
+        assert_eq!(productions.len(), 1);
+        assert_eq!(productions[0].weight, 1.0);
+        assert!(productions[0].is_context_free());
+        let successor: Vec<TurtleStep> = productions[0]
+            .successor
+            .iter()
+            .map(|t| koch.symbols.step(t.symbol).clone())
+            .collect();
         assert_eq!(
-            koch.rewrite_rules,
-            [(
+            successor,
+            vec![
+                TurtleStep::Forward('F'),
+                TurtleStep::TurnLeft,
                 TurtleStep::Forward('F'),
-                vec![
-                    TurtleStep::Forward('F'),
-                    TurtleStep::TurnLeft,
-                    TurtleStep::Forward('F'),
-                    TurtleStep::TurnRight,
-                ]
-            )]
-            .into_iter()
-            .collect()
+                TurtleStep::TurnRight,
+            ]
         );
     }
 
     #[test]
     fn dragon() {
-        let dragon = Koch::dragon();
-        assert_eq!(dragon.initial_state, vec![TurtleStep::Forward('L')]);
+        let dragon = builtin_koch("dragon");
+        assert_eq!(symbols(&state(&dragon)), vec![TurtleStep::Forward('L')]);
     }
 
     #[test]
     fn quad_gosper() {
-        let quad_gosper = Koch::quad_gosper();
+        let quad_gosper = builtin_koch("quad_gosper");
         assert_eq!(
-            quad_gosper.initial_state,
+            symbols(&state(&quad_gosper)),
             vec![TurtleStep::TurnRight, TurtleStep::Forward('R')]
         );
     }
 
     #[test]
     fn step() {
-        let mut koch = super::Koch::from("test", "F", "F => F+F-", 0, FRAC_PI_2);
+        let mut koch =
+            Koch::parse("test", "F", "F => F+F-", DEFAULT_ALPHABET, 0, FRAC_PI_2).unwrap();
         koch.next_iteration();
         assert_eq!(
-            koch.state,
+            symbols(&state(&koch)),
             vec![
                 TurtleStep::Forward('F'),
                 TurtleStep::TurnLeft,
@@ -380,7 +1053,7 @@ mod tests {
         );
         koch.next_iteration();
         assert_eq!(
-            koch.state,
+            symbols(&state(&koch)),
             vec![
                 TurtleStep::Forward('F'),
                 TurtleStep::TurnLeft,
@@ -395,4 +1068,430 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn rotations() {
+        let koch = Koch::parse("test", "F&F^F\\F/F|", "", DEFAULT_ALPHABET, 0, FRAC_PI_2).unwrap();
+        assert_eq!(
+            symbols(&state(&koch)),
+            vec![
+                TurtleStep::Forward('F'),
+                TurtleStep::PitchDown,
+                TurtleStep::Forward('F'),
+                TurtleStep::PitchUp,
+                TurtleStep::Forward('F'),
+                TurtleStep::RollLeft,
+                TurtleStep::Forward('F'),
+                TurtleStep::RollRight,
+                TurtleStep::Forward('F'),
+                TurtleStep::TurnAround,
+            ]
+        );
+    }
+
+    #[test]
+    fn alphabet() {
+        let koch = |axiom: &str, draw: &str, moves: &str| {
+            let spec = KochSpec {
+                draw: draw.to_string(),
+                moves: moves.to_string(),
+                ..spec(axiom, &[], 0)
+            };
+            Koch::try_from_spec(&spec).unwrap()
+        };
+        assert_eq!(
+            symbols(&state(&koch("ABFGf", "AB", "G"))),
+            vec![
+                TurtleStep::Forward('A'),
+                TurtleStep::Forward('B'),
+                TurtleStep::Node('F'),
+                TurtleStep::ForwardNoLine('G'),
+                TurtleStep::Node('f'),
+            ]
+        );
+        let spec: KochSpec = toml::from_str(
+            "name = \"test\"\naxiom = \"FLRfA\"\nproductions = []\niterations = 0\nangle = 90",
+        )
+        .unwrap();
+        assert_eq!(
+            symbols(&state(&Koch::try_from_spec(&spec).unwrap())),
+            vec![
+                TurtleStep::Forward('F'),
+                TurtleStep::Forward('L'),
+                TurtleStep::Forward('R'),
+                TurtleStep::ForwardNoLine('f'),
+                TurtleStep::Node('A'),
+            ]
+        );
+    }
+
+    #[test]
+    fn generation() {
+        let koch = Koch::parse("test", "F", "F => F+F-", DEFAULT_ALPHABET, 0, FRAC_PI_2).unwrap();
+        assert_eq!(koch.generation(), None);
+        let mut koch = builtin_koch("tree_stochastic");
+        koch.set_seed(7);
+        let generation = koch.generation().unwrap();
+        assert_eq!(generation.children, koch.modules().collect::<Vec<_>>());
+        koch.set_n(koch.get_n() - 1);
+        assert_eq!(generation.parents, koch.modules().collect::<Vec<_>>());
+
+        let koch = Koch::parse("test", "F+F", "F => F+F-", DEFAULT_ALPHABET, 1, FRAC_PI_2).unwrap();
+        let generation = koch.generation().unwrap();
+        assert_eq!(generation.origins, vec![0, 0, 0, 0, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn iterations() {
+        let mut koch =
+            Koch::parse("test", "F", "F => F+F-", DEFAULT_ALPHABET, 2, FRAC_PI_2).unwrap();
+        let state: Vec<Module> = koch.modules().collect();
+        koch.set_n(3);
+        assert_eq!(koch.modules().count(), 22);
+        koch.set_n(2);
+        assert_eq!(koch.modules().collect::<Vec<_>>(), state);
+        koch.set_n(0);
+        let state: Vec<Module> = koch.modules().collect();
+        assert_eq!(symbols(&state), vec![TurtleStep::Forward('F')]);
+    }
+
+    #[test]
+    fn lazy() {
+        // expanding on demand yields the same modules as rewriting whole levels
+        let eager = |koch: &Koch| {
+            let mut koch = koch.clone();
+            koch.lazy = false;
+            koch.regenerate();
+            state(&koch)
+        };
+        for name in [
+            "dragon",
+            "tree_stochastic",
+            "tree_parametric",
+            "tree_signal",
+        ] {
+            let mut koch = builtin_koch(name);
+            for seed in 0..3 {
+                koch.set_seed(seed);
+                assert_eq!(koch.modules().collect::<Vec<_>>(), eager(&koch), "{}", name);
+            }
+        }
+
+        let mut koch = builtin_koch("dragon");
+        let state: Vec<Module> = koch.modules().collect();
+        for module in state {
+            assert_eq!(koch.next_step(), module);
+        }
+        assert_eq!(koch.next_step().step, TurtleStep::Reset);
+        assert_eq!(koch.next_step().step, TurtleStep::Forward('L'));
+    }
+
+    // the lazy expansion yields the modules of the built level while holding only the
+    // successors on the way down to the current one
+    #[test]
+    fn expansion() {
+        for (name, n) in [("dragon", 14), ("islands", 3), ("tree_stochastic", 7)] {
+            let mut koch = builtin_koch(name);
+            koch.set_n(n);
+            let mut modules = koch.modules();
+            let mut lazy = Vec::new();
+            let mut held = 0;
+            while let Some(module) = modules.next() {
+                lazy.push(module);
+                let frames = modules.cursor.frames.iter();
+                held = held.max(frames.map(|(word, _)| word.bytes()).sum());
+            }
+            koch.lazy = false;
+            koch.regenerate();
+            assert_eq!(lazy, state(&koch), "{}", name);
+            assert!(
+                held * 100 < koch.state.bytes(),
+                "{}: lazy {} bytes, eager {} bytes",
+                name,
+                held,
+                koch.state.bytes()
+            );
+        }
+        // streams are only made for systems with productions to pick among
+        assert!(builtin_koch("dragon").modules().cursor.rngs.is_empty());
+        assert_eq!(
+            builtin_koch("tree_stochastic").modules().cursor.rngs.len(),
+            5
+        );
+    }
+
+    // cargo test --release -- --ignored --nocapture timing
+    #[test]
+    #[ignore]
+    fn timing() {
+        use std::time::Instant;
+        for (name, n) in [("dragon", 18), ("islands", 4), ("tree_stochastic", 9)] {
+            let mut koch = builtin_koch(name);
+            koch.set_n(n);
+            let start = Instant::now();
+            let count = koch.modules().count();
+            let lazy = start.elapsed();
+            koch.lazy = false;
+            let start = Instant::now();
+            koch.regenerate();
+            let eager = start.elapsed();
+            assert_eq!(koch.state.len(), count);
+            println!(
+                "{} n = {}: {} modules, lazy {:?}, eager {:?}",
+                name, n, count, lazy, eager
+            );
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture encoding
+    #[test]
+    #[ignore]
+    fn encoding() {
+        use std::time::Instant;
+        for (name, n) in [("dragon", 18), ("islands", 4), ("hex_gosper", 6)] {
+            let mut koch = builtin_koch(name);
+            koch.lazy = false;
+            // rewriting a Vec<Module> by cloning the successor of each module, as before the
+            // modules were encoded
+            let rules: HashMap<TurtleStep, Vec<Module>> = koch
+                .rewrite_rules
+                .iter()
+                .enumerate()
+                .filter_map(|(symbol, productions)| {
+                    let successor = productions.first()?.successor.iter();
+                    let modules = successor.map(|t| koch.symbols.module(t.symbol, &[]));
+                    Some((
+                        koch.symbols.step(symbol as Symbol).clone(),
+                        modules.collect(),
+                    ))
+                })
+                .collect();
+            let start = Instant::now();
+            let mut modules = koch.symbols.decode(&koch.initial_state);
+            for _ in 0..n {
+                modules = modules
+                    .iter()
+                    .flat_map(|m| {
+                        rules
+                            .get(&m.step)
+                            .cloned()
+                            .unwrap_or_else(|| vec![m.clone()])
+                    })
+                    .collect();
+            }
+            let decoded = start.elapsed();
+            let start = Instant::now();
+            koch.set_n(n);
+            let encoded = start.elapsed();
+            assert_eq!(modules, state(&koch));
+            println!(
+                "{} n = {}: {} modules, as modules {:?} holding {} KiB, as symbols {:?} holding {} KiB",
+                name,
+                n,
+                modules.len(),
+                decoded,
+                (modules.len() * std::mem::size_of::<Module>()) >> 10,
+                encoded,
+                koch.state.bytes() >> 10
+            );
+        }
+    }
+
+    #[test]
+    fn stochastic() {
+        let koch = Koch::parse(
+            "test",
+            "F",
+            "F (0.2)=> F+\nF (0.8)=> F-",
+            DEFAULT_ALPHABET,
+            0,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        let weights: Vec<f32> = koch.rewrite_rules[koch.state.symbol(0) as usize]
+            .iter()
+            .map(|p| p.weight)
+            .collect();
+        assert_eq!(weights, vec![0.2, 0.8]);
+
+        let mut a = builtin_koch("tree_stochastic");
+        let mut b = builtin_koch("tree_stochastic");
+        let modules = |koch: &Koch| koch.modules().collect::<Vec<_>>();
+        assert_eq!(modules(&a), modules(&b));
+        a.set_seed(1);
+        b.set_seed(1);
+        assert_eq!(modules(&a), modules(&b));
+        b.set_seed(2);
+        assert_ne!(modules(&a), modules(&b));
+
+        // level k of one seed does not draw the stream of level k - 1 of the next seed
+        let draws = |seed, level| -> Vec<u32> {
+            let mut rng = level_rng(seed, level);
+            (0..4).map(|_| rng.gen()).collect()
+        };
+        for seed in 0..10 {
+            assert_ne!(draws(seed, 3), draws(seed + 1, 2));
+            assert_ne!(draws(seed, 0), draws(seed, 1));
+        }
+    }
+
+    #[test]
+    fn parametric() {
+        let mut koch = Koch::parse(
+            "test",
+            "A(2)",
+            "A(l) => F(l)[+(30)A(l*0.5-(1-1))]",
+            DEFAULT_ALPHABET,
+            0,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        assert_eq!(
+            state(&koch),
+            vec![Module {
+                step: TurtleStep::Node('A'),
+                params: vec![2.0]
+            }]
+        );
+        koch.next_iteration();
+        koch.next_iteration();
+        let params: Vec<Vec<f32>> = state(&koch).iter().map(|m| m.params.clone()).collect();
+        assert_eq!(
+            params,
+            vec![
+                vec![2.0],
+                vec![],
+                vec![30.0],
+                vec![1.0],
+                vec![],
+                vec![30.0],
+                vec![0.5],
+                vec![],
+                vec![]
+            ]
+        );
+
+        // productions only apply to modules with a matching number of parameters
+        let mut koch =
+            Koch::parse("test", "F(1)F", "F => FF", DEFAULT_ALPHABET, 1, FRAC_PI_2).unwrap();
+        assert_eq!(koch.modules().count(), 3);
+        koch.set_n(2);
+        assert_eq!(koch.modules().count(), 5);
+    }
+
+    #[test]
+    fn context() {
+        // a signal travels from the base to the tip, one module per iteration
+        let mut koch = Koch::parse(
+            "test",
+            "baaa",
+            "b < a => b\nb => a",
+            DEFAULT_ALPHABET,
+            0,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        koch.next_iteration();
+        assert_eq!(symbols(&state(&koch)), symbols(&steps("abaa")));
+        koch.next_iteration();
+        assert_eq!(symbols(&state(&koch)), symbols(&steps("aaba")));
+
+        // the left context is found on the path to the root, skipping completed branches
+        let mut koch = Koch::parse(
+            "test",
+            "b[a]a[a[a]a]",
+            "b < a => b",
+            DEFAULT_ALPHABET,
+            1,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        assert_eq!(symbols(&state(&koch)), symbols(&steps("b[b]b[a[a]a]")));
+        koch.next_iteration();
+        assert_eq!(symbols(&state(&koch)), symbols(&steps("b[b]b[b[a]a]")));
+
+        // the right context skips lateral branches and ignored symbols
+        let koch = Koch::parse(
+            "test",
+            "a[b]+c",
+            "#ignore: +\na > c => c",
+            DEFAULT_ALPHABET,
+            1,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        assert_eq!(symbols(&state(&koch)), symbols(&steps("c[b]+c")));
+
+        // parameters are bound across the context and the predecessor
+        let koch = Koch::parse(
+            "test",
+            "a(1)b(2)",
+            "a(x) < b(y) => b(x+y)",
+            DEFAULT_ALPHABET,
+            1,
+            FRAC_PI_2,
+        )
+        .unwrap();
+        assert_eq!(state(&koch)[1].params, vec![3.0]);
+    }
+
+    #[test]
+    fn errors() {
+        let spec = |axiom: &str, productions: &[&str]| spec(axiom, productions, 1);
+        let err = Koch::try_from_spec(&spec("F-F)", &["F => FF"])).unwrap_err();
+        assert!(matches!(
+            err,
+            KochError::Syntax {
+                section: Section::Axiom,
+                line: 1,
+                col: 4,
+                ..
+            }
+        ));
+        let err = Koch::try_from_spec(&spec("F", &["F => FF", "F =>"])).unwrap_err();
+        assert!(matches!(
+            err,
+            KochError::Syntax {
+                section: Section::Productions,
+                line: 2,
+                ..
+            }
+        ));
+        let err = Koch::try_from_spec(&spec("A(1)", &["A(l) => F(x)"])).unwrap_err();
+        assert_eq!(
+            err,
+            KochError::UnknownParameter {
+                section: Section::Productions,
+                line: 1,
+                col: 11,
+                name: "x".to_string()
+            }
+        );
+        assert_eq!(err.to_string(), "productions 1:11: unknown parameter `x`");
+    }
+
+    #[test]
+    fn limit() {
+        let dragon = builtin_koch("dragon");
+        assert_eq!(dragon.estimate_len(12), dragon_len(12) as f64);
+        assert!(dragon.check_n(16).is_ok());
+        let err = dragon.check_n(30).unwrap_err();
+        assert_eq!(err, KochError::TooLarge { n: 30 });
+        // presets asking for too many levels do not load
+        let spec = KochSpec {
+            iterations: 30,
+            ..builtin_spec("islands")
+        };
+        assert!(matches!(
+            Koch::try_from_spec(&spec),
+            Err(KochError::TooLarge { n: 30 })
+        ));
+    }
+
+    #[test]
+    fn builtins() {
+        for spec in preset::builtin() {
+            assert!(Koch::try_from_spec(&spec).is_ok(), "{}", spec.name);
+        }
+    }
 }
@@ -0,0 +1,488 @@
+use super::{Koch, Module, TurtleStep};
+
+use glam::Vec3;
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub depth: usize, // bracket depth of the branch it belongs to
+    pub step: usize,  // index of the module that drew it
+    pub width: f32,   // relative width set by !
+    pub color: f32,   // colour index set by '
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Polyline {
+    pub points: Vec<Vec3>,
+    pub depth: usize,
+}
+
+// A filled shape outlined between { and }
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Polygon {
+    pub points: Vec<Vec3>,
+    pub depth: usize,
+    pub color: f32,
+}
+
+// What a module leaves on the page
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Segment(Segment),
+    Polygon(Polygon),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Geometry {
+    pub segments: Vec<Segment>,
+    pub polylines: Vec<Polyline>,
+    pub polygons: Vec<Polygon>,
+}
+
+// Size of a drawing, found without keeping its shapes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Extent {
+    pub bounds: Option<(Vec3, Vec3)>,
+    pub segments: usize,
+    pub length: f32,      // total length of the segments
+    pub max_depth: usize, // deepest branch with a segment
+}
+
+// Orientation of the turtle: heading, left and up, as in The Algorithmic Beauty of Plants.
+// It starts in the xy plane heading along x, so planar systems draw as they always have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    heading: Vec3,
+    left: Vec3,
+    up: Vec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pen {
+    width: f32,
+    color: f32,
+}
+
+// The turtle state machine, fed one module at a time
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    xyz: Vec3,
+    frame: Frame,
+    pen: Pen,
+    length: f32,                    // length of step
+    delta: f32,                     // default turning angle
+    stack: Vec<(Vec3, Frame, Pen)>, // stack of (xyz, frame, pen)
+    polygons: Vec<Polygon>,         // polygons being outlined, innermost last
+    step: usize,                    // number of modules executed since the last reset
+}
+
+const WIDTH_FACTOR: f32 = 0.7; // applied by a ! without parameter
+
+impl Geometry {
+    // Smallest and largest corner of the drawn segments
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let ends = self.segments.iter().flat_map(|s| [s.start, s.end]);
+        let mut points = ends.chain(self.polygons.iter().flat_map(|p| p.points.iter().copied()));
+        let first = points.next()?;
+        Some(points.fold((first, first), |(min, max), p| (min.min(p), max.max(p))))
+    }
+}
+
+impl Extent {
+    fn include(&mut self, p: Vec3) {
+        self.bounds = Some(match self.bounds {
+            Some((min, max)) => (min.min(p), max.max(p)),
+            None => (p, p),
+        });
+    }
+}
+
+// Rotates the pair of axes (a, b) by angle within their plane, turning a towards b
+fn rotate(a: &mut Vec3, b: &mut Vec3, angle: f32) {
+    let (sin, cos) = angle.sin_cos();
+    let (a0, b0) = (*a, *b);
+    *a = a0 * cos + b0 * sin;
+    *b = b0 * cos - a0 * sin;
+}
+
+impl Default for Pen {
+    fn default() -> Self {
+        Self {
+            width: 1.0,
+            color: 0.0,
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self {
+            heading: Vec3::X,
+            left: Vec3::Y,
+            up: Vec3::Z,
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new(length: f32, delta: f32) -> Self {
+        Self {
+            xyz: Vec3::ZERO,
+            frame: Frame::default(),
+            pen: Pen::default(),
+            length,
+            delta,
+            stack: Vec::new(),
+            polygons: Vec::new(),
+            step: 0,
+        }
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.xyz
+    }
+
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.length, self.delta);
+    }
+
+    // Executes a module and returns the shape it draws, if any. The first parameter, if
+    // any, scales the step length, sets the angle in degrees or sets the width or colour.
+    pub fn step(&mut self, module: &Module) -> Option<Shape> {
+        let Module { step, params } = module;
+        let index = self.step;
+        self.step += 1;
+        let length = params.first().map_or(self.length, |l| self.length * l);
+        let delta = params.first().map_or(self.delta, |d| d.to_radians());
+        let Frame { heading, left, up } = &mut self.frame;
+        match step {
+            TurtleStep::Forward(_) => {
+                let start = self.xyz;
+                self.forward(length);
+                return Some(Shape::Segment(Segment {
+                    start,
+                    end: self.xyz,
+                    depth: self.depth(),
+                    step: index,
+                    width: self.pen.width,
+                    color: self.pen.color,
+                }));
+            }
+            TurtleStep::ForwardNoLine(_) => self.forward(length),
+            TurtleStep::BeginPolygon => self.polygons.push(Polygon {
+                points: Vec::new(),
+                depth: self.stack.len(),
+                color: 0.0,
+            }),
+            TurtleStep::Vertex => self.vertex(),
+            TurtleStep::EndPolygon => {
+                // fewer than three vertices enclose nothing
+                if let Some(polygon) = self.polygons.pop().filter(|p| p.points.len() > 2) {
+                    let color = self.pen.color; // as set while outlining
+                    return Some(Shape::Polygon(Polygon { color, ..polygon }));
+                }
+            }
+            TurtleStep::TurnLeft => rotate(heading, left, delta),
+            TurtleStep::TurnRight => rotate(heading, left, -delta),
+            TurtleStep::PitchDown => rotate(heading, up, -delta),
+            TurtleStep::PitchUp => rotate(heading, up, delta),
+            TurtleStep::RollLeft => rotate(left, up, -delta),
+            TurtleStep::RollRight => rotate(left, up, delta),
+            TurtleStep::TurnAround => rotate(heading, left, PI),
+            TurtleStep::Width => {
+                self.pen.width = params.first().map_or(self.pen.width * WIDTH_FACTOR, |&w| w)
+            }
+            TurtleStep::Color => {
+                self.pen.color = params.first().map_or(self.pen.color + 1.0, |&c| c)
+            }
+            TurtleStep::Push => self.stack.push((self.xyz, self.frame, self.pen)),
+            TurtleStep::Pop => {
+                // an unbalanced pop is ignored rather than unwinding past the root
+                if let Some((xyz, frame, pen)) = self.stack.pop() {
+                    self.xyz = xyz;
+                    self.frame = frame;
+                    self.pen = pen;
+                }
+            }
+            TurtleStep::Reset => self.reset(),
+            TurtleStep::Node(_) => (),
+        }
+        None
+    }
+
+    fn forward(&mut self, length: f32) {
+        self.xyz += self.frame.heading * length;
+    }
+
+    // Marks the position as the next vertex of the innermost polygon, as . does in ABOP
+    fn vertex(&mut self) {
+        if let Some(polygon) = self.polygons.last_mut() {
+            if polygon.points.last() != Some(&self.xyz) {
+                polygon.points.push(self.xyz);
+            }
+        }
+    }
+}
+
+fn finish(line: &mut Polyline, polylines: &mut Vec<Polyline>) {
+    let line = std::mem::take(line);
+    if line.points.len() > 1 {
+        polylines.push(line);
+    }
+}
+
+// Interprets the modules of the koch in one pass. Polylines are unbroken runs of a
+// branch; the polyline of a parent branch is resumed after its children are popped.
+pub fn interpret(koch: &Koch, length: f32) -> Geometry {
+    let mut interpreter = Interpreter::new(length, koch.get_delta());
+    let mut geometry = Geometry::default();
+    let mut line = Polyline::default();
+    let mut stack = Vec::new();
+    for module in koch.modules() {
+        match interpreter.step(&module) {
+            Some(Shape::Segment(segment)) => {
+                if line.points.is_empty() {
+                    line.points.push(segment.start);
+                    line.depth = segment.depth;
+                }
+                line.points.push(segment.end);
+                geometry.segments.push(segment);
+            }
+            Some(Shape::Polygon(polygon)) => geometry.polygons.push(polygon),
+            None => match module.step {
+                TurtleStep::Push => stack.push(std::mem::take(&mut line)),
+                TurtleStep::Pop => {
+                    finish(&mut line, &mut geometry.polylines);
+                    line = stack.pop().unwrap_or_default();
+                }
+                TurtleStep::ForwardNoLine(_) => finish(&mut line, &mut geometry.polylines),
+                _ => (),
+            },
+        }
+    }
+    finish(&mut line, &mut geometry.polylines);
+    while let Some(mut line) = stack.pop() {
+        finish(&mut line, &mut geometry.polylines);
+    }
+    geometry
+}
+
+// Measures the drawing of the koch in one pass over its modules, as they are expanded
+pub fn measure(koch: &Koch, length: f32) -> Extent {
+    let mut interpreter = Interpreter::new(length, koch.get_delta());
+    let mut extent = Extent::default();
+    for module in koch.modules() {
+        match interpreter.step(&module) {
+            Some(Shape::Segment(segment)) => {
+                extent.include(segment.start);
+                extent.include(segment.end);
+                extent.segments += 1;
+                extent.length += segment.start.distance(segment.end);
+                extent.max_depth = extent.max_depth.max(segment.depth);
+            }
+            Some(Shape::Polygon(polygon)) => {
+                for &p in polygon.points.iter() {
+                    extent.include(p);
+                }
+            }
+            None => (),
+        }
+    }
+    extent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::fixtures::{builtin_koch, koch};
+
+    fn round(points: &[Vec3]) -> Vec<(i32, i32)> {
+        points
+            .iter()
+            .map(|p| (p.x.round() as i32, p.y.round() as i32))
+            .collect()
+    }
+
+    #[test]
+    fn branches() {
+        let geometry = interpret(&koch("F[+F]F[-F]F", &[], 0), 1.0);
+        let lines: Vec<_> = geometry
+            .polylines
+            .iter()
+            .map(|l| round(&l.points))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                vec![(1, 0), (1, 1)],
+                vec![(2, 0), (2, -1)],
+                vec![(0, 0), (1, 0), (2, 0), (3, 0)],
+            ]
+        );
+        let depths: Vec<_> = geometry.polylines.iter().map(|l| l.depth).collect();
+        assert_eq!(depths, vec![1, 1, 0]);
+        let steps: Vec<_> = geometry
+            .segments
+            .iter()
+            .map(|s| (s.step, s.depth))
+            .collect();
+        assert_eq!(steps, vec![(0, 0), (3, 1), (5, 0), (8, 1), (10, 0)]);
+
+        let geometry = interpret(&koch("FfF+F", &[], 0), 2.0);
+        let lines: Vec<_> = geometry
+            .polylines
+            .iter()
+            .map(|l| round(&l.points))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![vec![(0, 0), (2, 0)], vec![(4, 0), (6, 0), (6, 2)]]
+        );
+    }
+
+    #[test]
+    fn parameters() {
+        let geometry = interpret(&koch("F(2)+(45)F-(45)F(0.5)", &[], 0), 10.0);
+        let ends: Vec<_> = geometry.segments.iter().map(|s| s.end).collect();
+        let expected = [
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(27.071, 7.071, 0.0),
+            Vec3::new(32.071, 7.071, 0.0),
+        ];
+        for (end, expected) in ends.iter().zip(expected) {
+            assert!(end.abs_diff_eq(expected, 1e-3), "{} != {}", end, expected);
+        }
+    }
+
+    #[test]
+    fn tree_a() {
+        let mut koch = builtin_koch("tree_a");
+
+        // segments after a pop start back on the stem, not at the tip of the branch
+        koch.set_n(1);
+        let geometry = interpret(&koch, 1.0);
+        let expected = [
+            ((0.0, 0.0), (1.0, 0.0)),
+            ((1.0, 0.0), (1.901, 0.434)),
+            ((1.0, 0.0), (2.0, 0.0)),
+            ((2.0, 0.0), (2.901, -0.434)),
+            ((2.0, 0.0), (3.0, 0.0)),
+        ];
+        assert_eq!(geometry.segments.len(), expected.len());
+        for (segment, (start, end)) in geometry.segments.iter().zip(expected) {
+            let (start, end) = (
+                Vec3::new(start.0, start.1, 0.0),
+                Vec3::new(end.0, end.1, 0.0),
+            );
+            assert!(
+                segment.start.abs_diff_eq(start, 1e-3) && segment.end.abs_diff_eq(end, 1e-3),
+                "{:?} != {} -> {}",
+                segment,
+                start,
+                end
+            );
+        }
+
+        // the trunk stays one straight unbroken line however deep the branches nest
+        koch.set_n(3);
+        let geometry = interpret(&koch, 1.0);
+        assert_eq!(geometry.segments.len(), 125);
+        let trunk: Vec<_> = geometry.segments.iter().filter(|s| s.depth == 0).collect();
+        assert_eq!(trunk.len(), 27);
+        for (i, segment) in trunk.iter().enumerate() {
+            assert!(segment
+                .start
+                .abs_diff_eq(Vec3::new(i as f32, 0.0, 0.0), 1e-4));
+            assert!(segment
+                .end
+                .abs_diff_eq(Vec3::new(i as f32 + 1.0, 0.0, 0.0), 1e-4));
+        }
+        let stem = geometry.polylines.last().unwrap();
+        assert_eq!(
+            round(&[stem.points[0], *stem.points.last().unwrap()]),
+            vec![(0, 0), (27, 0)]
+        );
+    }
+
+    #[test]
+    fn unbalanced() {
+        let geometry = interpret(&koch("F]]F[F", &[], 0), 1.0);
+        assert_eq!(geometry.segments.len(), 3);
+        let (min, max) = geometry.bounds().unwrap();
+        assert_eq!(round(&[min, max]), vec![(0, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn frame() {
+        let ends = |axiom: &str| -> Vec<(i32, i32, i32)> {
+            let geometry = interpret(&koch(axiom, &[], 0), 1.0);
+            let end = |p: Vec3| (p.x.round() as i32, p.y.round() as i32, p.z.round() as i32);
+            geometry.segments.iter().map(|s| end(s.end)).collect()
+        };
+        assert_eq!(ends("&F"), vec![(0, 0, -1)]);
+        assert_eq!(ends("^F"), vec![(0, 0, 1)]);
+        assert_eq!(ends("|F"), vec![(-1, 0, 0)]);
+        // rolling turns the plane in which + and - turn
+        assert_eq!(ends("\\+F"), vec![(0, 0, -1)]);
+        assert_eq!(ends("/+F"), vec![(0, 0, 1)]);
+        assert_eq!(ends("[&F]F"), vec![(0, 0, -1), (1, 0, 0)]);
+    }
+
+    #[test]
+    fn pen() {
+        let geometry = interpret(&koch("F!F[!(0.2)'F]F'(3)F", &[], 0), 1.0);
+        let pens: Vec<(f32, f32)> = geometry
+            .segments
+            .iter()
+            .map(|s| ((s.width * 100.0).round() / 100.0, s.color))
+            .collect();
+        assert_eq!(
+            pens,
+            vec![(1.0, 0.0), (0.7, 0.0), (0.2, 1.0), (0.7, 0.0), (0.7, 3.0)]
+        );
+    }
+
+    #[test]
+    fn polygons() {
+        let geometry = interpret(&koch("{.f.+f.+f.}F{.f.}[{.'f.+F.+f.}]{f+f+f}", &[], 0), 1.0);
+        let polygons: Vec<Vec<(i32, i32)>> =
+            geometry.polygons.iter().map(|p| round(&p.points)).collect();
+        assert_eq!(
+            polygons,
+            vec![
+                vec![(0, 0), (1, 0), (1, 1), (0, 1)],
+                // a polygon may be outlined with drawing moves, a single edge is dropped and
+                // moves mark no vertices without a .
+                vec![(-2, 1), (-3, 1), (-3, 0), (-2, 0)],
+            ]
+        );
+        assert_eq!(geometry.polygons[1].depth, 1);
+        assert_eq!(geometry.polygons[1].color, 1.0);
+        assert_eq!(geometry.segments.len(), 2);
+    }
+
+    #[test]
+    fn extent() {
+        for name in ["tree_a", "tree_leaves", "bush"] {
+            let koch = builtin_koch(name);
+            let geometry = interpret(&koch, 2.0);
+            let extent = measure(&koch, 2.0);
+            assert_eq!(extent.bounds, geometry.bounds(), "{}", name);
+            assert_eq!(extent.segments, geometry.segments.len(), "{}", name);
+            let length: f32 = geometry
+                .segments
+                .iter()
+                .map(|s| s.start.distance(s.end))
+                .sum();
+            assert_eq!(extent.length, length, "{}", name);
+            let max_depth = geometry.segments.iter().map(|s| s.depth).max();
+            assert_eq!(extent.max_depth, max_depth.unwrap(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use super::{KochError, Module, TurtleStep};
+use std::collections::HashMap;
+
+// Code of a (step, arity) pair in a SymbolTable
+pub type Symbol = u16;
+
+// Numbers the distinct modules of an L-system so that strings can be stored as small codes.
+// Rewriting only produces modules found in the axiom or a successor, so the table is complete
+// once the definition is parsed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    entries: Vec<(TurtleStep, usize)>,
+    codes: HashMap<(TurtleStep, usize), Symbol>,
+}
+
+// A string of modules, with the parameters of all the modules laid end to end
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Word {
+    symbols: Vec<Symbol>,
+    offsets: Vec<u32>, // start of the parameters of each module
+    params: Vec<f32>,
+}
+
+impl SymbolTable {
+    pub fn intern(&mut self, step: &TurtleStep, arity: usize) -> Result<Symbol, KochError> {
+        let key = (step.clone(), arity);
+        if let Some(&symbol) = self.codes.get(&key) {
+            return Ok(symbol);
+        }
+        let symbol = Symbol::try_from(self.entries.len()).map_err(|_| KochError::TooManySymbols)?;
+        self.entries.push(key.clone());
+        self.codes.insert(key, symbol);
+        Ok(symbol)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn step(&self, symbol: Symbol) -> &TurtleStep {
+        &self.entries[symbol as usize].0
+    }
+
+    #[cfg(test)]
+    pub fn encode(&mut self, modules: &[Module]) -> Result<Word, KochError> {
+        let mut word = Word::default();
+        for module in modules {
+            let symbol = self.intern(&module.step, module.params.len())?;
+            word.push(symbol, module.params.iter().copied());
+        }
+        Ok(word)
+    }
+
+    pub fn module(&self, symbol: Symbol, params: &[f32]) -> Module {
+        Module {
+            step: self.step(symbol).clone(),
+            params: params.to_vec(),
+        }
+    }
+
+    pub fn decode(&self, word: &Word) -> Vec<Module> {
+        (0..word.len())
+            .map(|i| self.module(word.symbol(i), word.params(i)))
+            .collect()
+    }
+}
+
+impl Word {
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn symbol(&self, i: usize) -> Symbol {
+        self.symbols[i]
+    }
+
+    pub fn params(&self, i: usize) -> &[f32] {
+        let start = self.offsets[i] as usize;
+        let end = self
+            .offsets
+            .get(i + 1)
+            .map_or(self.params.len(), |&o| o as usize);
+        &self.params[start..end]
+    }
+
+    pub fn push(&mut self, symbol: Symbol, params: impl IntoIterator<Item = f32>) {
+        self.symbols.push(symbol);
+        self.offsets.push(self.params.len() as u32);
+        self.params.extend(params);
+    }
+
+    // Memory held by the modules, for comparing with a Vec<Module>
+    #[cfg(test)]
+    pub fn bytes(&self) -> usize {
+        let per_module = std::mem::size_of::<Symbol>() + std::mem::size_of::<u32>();
+        self.len() * per_module + self.params.len() * std::mem::size_of::<f32>()
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+        self.offsets.clear();
+        self.params.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let modules = vec![
+            Module {
+                step: TurtleStep::Forward('F'),
+                params: vec![1.0, 2.0],
+            },
+            TurtleStep::Push.into(),
+            Module {
+                step: TurtleStep::Forward('F'),
+                params: vec![3.0],
+            },
+            TurtleStep::Pop.into(),
+            Module {
+                step: TurtleStep::Forward('F'),
+                params: vec![4.0, 5.0],
+            },
+        ];
+        let mut table = SymbolTable::default();
+        let word = table.encode(&modules).unwrap();
+        // modules with the same step and number of parameters share a symbol
+        assert_eq!(table.len(), 4);
+        assert_eq!(word.symbol(0), word.symbol(4));
+        assert_ne!(word.symbol(0), word.symbol(2));
+        assert_eq!(word.params(1), &[] as &[f32]);
+        assert_eq!(word.params(4), &[4.0, 5.0]);
+        assert_eq!(table.decode(&word), modules);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut table = SymbolTable::default();
+        let mut nodes = (0..).filter_map(char::from_u32).map(TurtleStep::Node);
+        for step in nodes.by_ref().take(Symbol::MAX as usize + 1) {
+            table.intern(&step, 0).unwrap();
+        }
+        assert_eq!(table.intern(&TurtleStep::Node('\0'), 0), Ok(0));
+        let step = nodes.next().unwrap();
+        assert_eq!(table.intern(&step, 0), Err(KochError::TooManySymbols));
+        let modules = [Module::from(step)];
+        assert_eq!(table.encode(&modules), Err(KochError::TooManySymbols));
+    }
+}
@@ -2,161 +2,1263 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
-#[allow(dead_code)]
+mod analysis;
+mod cli;
+mod escape;
+mod evolve;
+mod ifs;
 mod koch;
+mod mesh;
+mod morph;
+mod preset;
+mod raster;
+mod record;
+mod style;
+mod svg;
 
-use koch::{Koch, TurtleStep};
+use analysis::Stats;
+use clap::Parser;
+use cli::{Args, Command};
+use escape::{Escape, Formula};
+use evolve::Evolver;
+use ifs::{ChaosGame, Ifs, IfsSpec};
+use koch::interpreter::{interpret, measure, Interpreter, Polygon, Segment, Shape};
+use koch::{Koch, KochSpec, TurtleStep};
+use mesh::Mesh;
+use morph::Morph;
+use record::Recorder;
+use style::{ColorMode, Style};
 
-use crate::koch::KochModel;
 use nannou::prelude::*;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 struct Turtle {
-    xy: Vec2,
-    angle: f32,
     koch: Koch,
-    length: f32,                    // length of step
-    stack: Vec<(Vec2, f32)>,        // stack of (xy, angle)
-    steps: Vec<(TurtleStep, Vec2)>, // all steps to draw
+    length: f32, // length of step
+    interpreter: Interpreter,
+    bounds: Option<(Vec3, Vec3)>, // bounding box of the finished drawing
+    count: usize,                 // number of segments in the finished drawing
+    max_depth: usize,             // deepest branch of the finished drawing
+    style: Style,
+    pan: Vec2,   // user offset on top of the fit, in pixels
+    zoom: f32,   // user zoom on top of the fit
+    orbit: Vec2, // yaw and pitch of the camera around the drawing
+    perspective: bool,
+    growth: Option<Growth>, // set while growing the drawing level by level
+    growth_seconds: f32,    // time each level takes to grow
+    playback: Playback,
+    finished: bool, // whether the last step drawn was the end of the drawing
+    cache: Cache,
+    frame_time: f32,           // smoothed seconds per frame
+    stats: Option<Vec<Stats>>, // stats of each level, when shown
 }
 
-impl Turtle {
-    fn new(x: f32, y: f32, angle: f32, koch: Koch, length: f32) -> Turtle {
+// The shapes drawn so far as one mesh, extended as the turtle moves on and built again from
+// the modules when the colours change
+#[derive(Debug, Default)]
+struct Cache {
+    mesh: Mesh,
+    steps: usize,            // modules drawn into the mesh
+    segments: usize,         // segments in the mesh
+    mode: Option<ColorMode>, // colours of the mesh. The camera is applied when drawing.
+}
+
+// How fast the turtle draws, kept when switching presets
+#[derive(Debug, Clone, Copy)]
+struct Playback {
+    speed: usize,  // steps per frame
+    instant: bool, // draw everything in one frame
+    paused: bool,
+    hold: bool, // keep the finished drawing instead of starting over
+}
+
+impl Default for Playback {
+    fn default() -> Self {
         Self {
-            xy: vec2(x, y),
-            angle,
+            speed: 1,
+            instant: false,
+            paused: false,
+            hold: false,
+        }
+    }
+}
+
+// Growth of the drawing one level at a time, from the axiom up to n
+struct Growth {
+    level: usize,
+    morph: Morph,
+    time: f32, // seconds since the level started to grow
+}
+
+const MARGIN: f32 = 40.0;
+const ZOOM_STEP: f32 = 1.1;
+const ORBIT_SPEED: f32 = 0.01; // radians per pixel dragged
+const LINE_WIDTH: f32 = 0.0025; // width of the trunk relative to the size of the drawing
+const GROWTH_HOLD: f32 = 1.0; // seconds each grown level is shown before the next
+const GROWTH_STEP: f32 = 1.25;
+const MAX_SPEED: usize = 1 << 16;
+const GRID: usize = 3; // rows and columns of the gallery
+const THUMBNAIL_SEGMENTS: usize = 20_000; // most segments drawn in a thumbnail
+const THUMBNAIL_WIDTH: f32 = 0.004; // width of the trunk relative to the thumbnail
+const CHAOS_POINTS: usize = 200_000; // points the chaos game plays per frame
+const CHAOS_LIMIT: usize = 50_000_000; // points after which the chaos game stops
+const RENDER_POINTS: usize = 10_000_000; // points played for a rendered image
+const RECORD_STEP: f32 = 1.0 / 60.0; // seconds per frame while recording, for the same result every time
+const REFINE_BUDGET: Duration = Duration::from_millis(30); // escape-time rendering per frame
+
+impl Turtle {
+    fn new(koch: Koch, length: f32) -> Turtle {
+        let mut turtle = Self {
+            interpreter: Interpreter::new(length, koch.get_delta()),
+            bounds: None,
+            count: 0,
+            max_depth: 0,
+            style: Style::default(),
             koch,
             length,
-            stack: vec![(vec2(x, y), angle)],
-            steps: Vec::new(),
+            pan: Vec2::ZERO,
+            zoom: 1.0,
+            orbit: Vec2::ZERO,
+            perspective: false,
+            growth: None,
+            growth_seconds: 2.0,
+            playback: Playback::default(),
+            finished: false,
+            cache: Cache::default(),
+            frame_time: 0.0,
+            stats: None,
+        };
+        turtle.measure();
+        turtle
+    }
+
+    fn set_koch(&mut self, koch: Koch, style: Style) {
+        let growing = self.growth.is_some();
+        let growth_seconds = self.growth_seconds;
+        let playback = self.playback;
+        let stats = self.stats.is_some();
+        *self = Self::new(koch, self.length);
+        self.style = style;
+        self.growth_seconds = growth_seconds;
+        self.playback = playback;
+        if stats {
+            self.toggle_stats();
+        }
+        if growing {
+            self.grow(1);
+        }
+    }
+
+    // Starts growing the drawing into the given level from the one below
+    fn grow(&mut self, level: usize) {
+        let mut koch = self.koch.clone();
+        let level = level.min(koch.get_n());
+        koch.set_n(level);
+        self.growth = Some(Growth {
+            level,
+            morph: Morph::new(&koch, self.length),
+            time: 0.0,
+        });
+    }
+
+    fn toggle_growth(&mut self) {
+        match self.growth {
+            Some(_) => self.growth = None,
+            None => self.grow(1),
+        }
+    }
+
+    // Runs through the whole drawing up front to know how to fit and colour it
+    fn measure(&mut self) {
+        let extent = measure(&self.koch, self.length);
+        self.bounds = extent.bounds;
+        self.count = extent.segments;
+        self.max_depth = extent.max_depth;
+    }
+
+    // Applies a change to the koch and draws it again, keeping the user's pan and zoom
+    fn modify(&mut self, f: impl FnOnce(&mut Koch)) {
+        let delta = self.koch.get_delta();
+        f(&mut self.koch);
+        self.koch.rewind();
+        self.interpreter = Interpreter::new(self.length, self.koch.get_delta());
+        self.measure();
+        self.restart();
+        if self.growth.is_some() {
+            self.grow(1);
+        }
+        if let Some(stats) = &mut self.stats {
+            // the levels already measured only change with the angle
+            if self.koch.get_delta() != delta {
+                stats.clear();
+            }
+            analysis::extend(stats, &self.koch, self.length);
+        }
+    }
+
+    fn toggle_stats(&mut self) {
+        self.stats = match self.stats {
+            Some(_) => None,
+            None => Some(analysis::levels(&self.koch, self.length)),
+        };
+    }
+
+    // Forgets what has been drawn so far
+    fn restart(&mut self) {
+        self.cache = Cache {
+            mode: self.cache.mode,
+            ..Cache::default()
+        };
+        self.finished = false;
+    }
+
+    // Zooms by factor while keeping the point under the cursor in place
+    fn zoom_at(&mut self, cursor: Vec2, factor: f32) {
+        self.pan = cursor - (cursor - self.pan) * factor;
+        self.zoom *= factor;
+    }
+
+    fn reset_camera(&mut self) {
+        self.pan = Vec2::ZERO;
+        self.zoom = 1.0;
+        self.orbit = Vec2::ZERO;
+    }
+
+    // Projects points of the drawing onto the screen plane, relative to the center of its bounds.
+    // With no orbit and no perspective this is the plain xy view of a planar drawing.
+    fn projection(&self) -> impl Fn(Vec3) -> Vec2 {
+        let (min, max) = self.bounds.unwrap_or((Vec3::ZERO, Vec3::ZERO));
+        let center = (min + max) / 2.0;
+        let rotation = Quat::from_rotation_x(-self.orbit.y) * Quat::from_rotation_y(self.orbit.x);
+        // the eye sits three radii in front of the drawing
+        let eye = self
+            .perspective
+            .then(|| 1.5 * (max - min).length().max(f32::EPSILON));
+        move |p| {
+            let p = rotation * (p - center);
+            match eye {
+                Some(eye) => p.truncate() * eye / (eye - p.z).max(f32::EPSILON),
+                None => p.truncate(),
+            }
+        }
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.frame_time += (dt - self.frame_time) * 0.1;
+        self.tessellate();
+        self.draw_steps(dt);
+    }
+
+    // Moves the turtle on by the steps of one frame
+    fn draw_steps(&mut self, dt: f32) {
+        if self.playback.paused {
+            return;
+        }
+        if let Some(growth) = &mut self.growth {
+            growth.time += dt;
+            if growth.time > self.growth_seconds + GROWTH_HOLD {
+                // start over from the first level once the last one is grown
+                let next = growth.level + 1;
+                self.grow(if next > self.koch.get_n() { 1 } else { next });
+            }
+            return;
+        }
+        let steps = if self.playback.instant {
+            usize::MAX
+        } else {
+            self.playback.speed
+        };
+        for _ in 0..steps {
+            if self.finished {
+                // an instant drawing would only be drawn the same again
+                if self.playback.hold || self.playback.instant {
+                    return;
+                }
+                self.restart();
+            }
+            let module = self.koch.next_step();
+            if let Some(shape) = self.interpreter.step(&module) {
+                let mut cache = std::mem::take(&mut self.cache);
+                self.add(&mut cache, &shape);
+                self.cache = cache;
+            }
+            if module.step == TurtleStep::Reset {
+                // show the finished drawing for at least a frame
+                self.finished = true;
+                return;
+            }
+            self.cache.steps += 1;
+        }
+    }
+
+    // Draws the modules drawn so far into a new mesh if the colours have changed
+    fn tessellate(&mut self) {
+        if self.cache.mode == Some(self.style.mode) {
+            return;
         }
+        let mut cache = Cache {
+            steps: self.cache.steps,
+            mode: Some(self.style.mode),
+            ..Cache::default()
+        };
+        let mut interpreter = Interpreter::new(self.length, self.koch.get_delta());
+        for module in self.koch.modules().take(cache.steps) {
+            if let Some(shape) = interpreter.step(&module) {
+                self.add(&mut cache, &shape);
+            }
+        }
+        self.cache = cache;
     }
 
-    fn get_delta(&self) -> f32 {
-        self.koch.get_delta()
+    // Adds a shape to the mesh as it is drawn
+    fn add(&self, cache: &mut Cache, shape: &Shape) {
+        match shape {
+            Shape::Segment(segment) => {
+                self.forward(&mut cache.mesh, segment, cache.segments, self.count);
+                cache.segments += 1;
+            }
+            Shape::Polygon(polygon) => self.fill(&mut cache.mesh, polygon, cache.segments),
+        }
     }
 
-    fn set_koch(&mut self, koch: Koch) {
-        self.reset();
-        self.koch = koch;
+    // Doubles or halves the number of steps drawn per frame
+    fn set_speed(&mut self, faster: bool) {
+        let speed = self.playback.speed;
+        self.playback.speed = if faster {
+            (speed * 2).min(MAX_SPEED)
+        } else {
+            (speed / 2).max(1)
+        };
     }
 
-    fn update(&mut self) {
-        let length = self.length;
-        let delta = self.get_delta();
-        let step = self.koch.next_step();
-        match step {
-            TurtleStep::Forward(_) | TurtleStep::ForwardNoLine => self.forward_no_line(length),
-            TurtleStep::TurnLeft => self.turn_left(delta),
-            TurtleStep::TurnRight => self.turn_right(delta),
-            TurtleStep::Push => self.push(),
-            TurtleStep::Pop => self.pop(),
-            TurtleStep::Reset => self.reset(),
-            TurtleStep::Node(_) => (),
+    // Bounds of the finished drawing, or of the level being grown
+    fn drawing_bounds(&self) -> Option<(Vec3, Vec3)> {
+        match &self.growth {
+            Some(growth) => growth.morph.bounds(),
+            None => self.bounds,
         }
-        self.steps.push((step, self.xy));
+    }
+
+    // Width of the trunk in drawing units, so that lines zoom along with the drawing
+    fn line_width(&self) -> f32 {
+        self.drawing_bounds()
+            .map_or(0.0, |(min, max)| LINE_WIDTH * (max - min).max_element())
+    }
+
+    // Scale and offset that center the finished drawing in the window, keeping its aspect
+    fn fit(&self, win: Rect) -> (f32, Vec2) {
+        let (lo, hi) = match self.drawing_bounds() {
+            Some(bounds) => bounds,
+            None => return (1.0, Vec2::ZERO),
+        };
+        let project = self.projection();
+        let corners = (0..8).map(|i| {
+            let pick = |bit: usize, a: f32, b: f32| if i & bit == 0 { a } else { b };
+            project(vec3(
+                pick(1, lo.x, hi.x),
+                pick(2, lo.y, hi.y),
+                pick(4, lo.z, hi.z),
+            ))
+        });
+        let (min, max) = corners.fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), p| (min.min(p), max.max(p)),
+        );
+        let size = (max - min).max(Vec2::splat(f32::EPSILON));
+        let room = (win.wh() - Vec2::splat(2.0 * MARGIN)).max(Vec2::ONE);
+        let scale = (room / size).min_element();
+        (scale, (min + max) / 2.0)
+    }
+
+    // Draws the finished drawing onto an image of the given size
+    fn render(&mut self, width: u32, height: u32) -> nannou::image::RgbImage {
+        let win = Rect::from_w_h(width as f32, height as f32);
+        self.playback.instant = true;
+        self.update(0.0);
+        let (scale, center) = self.fit(win);
+        let scale = scale * self.zoom;
+        // from the screen plane to pixels, y down
+        let pixel = |p: Vec2| {
+            let p = (p - center) * scale + self.pan;
+            vec2(width as f32 / 2.0 + p.x, height as f32 / 2.0 - p.y)
+        };
+        let triangles = self
+            .cache
+            .mesh
+            .triangles(self.projection())
+            .map(|t| t.map(|(p, color)| (pixel(p), color)));
+        raster::rasterize(triangles, width, height, [0.0; 3])
     }
 
     fn draw(&self, app: &App, draw: &Draw) {
-        let mut prev_point = pt2(0.0, 0.0);
-        for (step, point) in self.steps.iter() {
-            match step {
-                TurtleStep::Forward(_) => self.forward(draw, prev_point, *point),
-                _ => (),
+        let (scale, center) = self.fit(app.window_rect());
+        let scale = scale * self.zoom;
+        let fitted = draw
+            .translate(self.pan.extend(0.0))
+            .scale(scale)
+            .translate(-center.extend(0.0));
+        match &self.growth {
+            Some(growth) => {
+                // ease in and out of each level
+                let t = (growth.time / self.growth_seconds).min(1.0);
+                let t = t * t * (3.0 - 2.0 * t);
+                let count = growth.morph.len();
+                let mut mesh = Mesh::default();
+                for (i, segment) in growth.morph.at(t).enumerate() {
+                    self.forward(&mut mesh, &segment, i, count);
+                }
+                mesh.draw(&fitted, self.projection());
             }
-            prev_point = *point;
+            None => self.cache.mesh.draw(&fitted, self.projection()),
         }
         self.draw_title(app, draw);
     }
 
     fn draw_title(&self, app: &App, draw: &Draw) {
         let win = app.window_rect();
-        let text_pos = Rect::from_w_h(100.0, 100.0).top_left_of(win.pad(30.0)).xy();
+        let text_rect = Rect::from_w_h(300.0, 100.0).top_left_of(win.pad(30.0));
         draw.text(self.koch.get_name())
-            .center_justify()
+            .left_justify()
+            .align_text_top()
             .color(WHITE)
             .font_size(24)
-            .xy(text_pos);
+            .wh(text_rect.wh())
+            .xy(text_rect.xy());
+        let mut info = format!(
+            "n = {}, angle = {:.1}°, colour by {}",
+            self.koch.get_n(),
+            self.koch.get_delta().to_degrees(),
+            self.style.mode
+        );
+        if let Some(growth) = &self.growth {
+            info += &format!(
+                "\ngrowing level {} in {:.1}s",
+                growth.level, self.growth_seconds
+            );
+        } else if self.playback.instant {
+            info += "\ninstant";
+        } else {
+            info += &format!("\n{} steps per frame", self.playback.speed);
+        }
+        if self.playback.hold {
+            info += ", hold";
+        }
+        if self.playback.paused {
+            info += ", paused";
+        }
+        info += &format!(
+            "\n{:.1} ms per frame, {} triangles",
+            self.frame_time * 1000.0,
+            self.cache.mesh.len()
+        );
+        draw.text(&info)
+            .left_justify()
+            .align_text_top()
+            .color(GRAY)
+            .font_size(14)
+            .wh(text_rect.wh())
+            .xy(text_rect.xy() - vec2(0.0, 32.0));
+        if let Some(stats) = &self.stats {
+            Self::draw_stats(stats, app, draw);
+        }
+    }
+
+    // A table of the stats of each level in the bottom left corner
+    fn draw_stats(stats: &[Stats], app: &App, draw: &Draw) {
+        const WIDTHS: [f32; 6] = [30.0, 70.0, 80.0, 120.0, 80.0, 80.0];
+        const LINE: f32 = 18.0;
+        let win = app.window_rect().pad(30.0);
+        let rows = analysis::rows(stats);
+        for (k, row) in rows.iter().enumerate() {
+            let y = win.bottom() + LINE * (rows.len() - k) as f32;
+            let mut x = win.left();
+            for (cell, width) in row.iter().zip(WIDTHS) {
+                draw.text(cell)
+                    .right_justify()
+                    .color(if k == 0 { WHITE } else { GRAY })
+                    .font_size(12)
+                    .w(width)
+                    .x_y(x + width / 2.0, y);
+                x += width;
+            }
+        }
+    }
+
+    // Adds the i-th of count segments to the mesh
+    fn forward(&self, mesh: &mut Mesh, segment: &Segment, i: usize, count: usize) {
+        mesh.line(
+            segment.start,
+            segment.end,
+            self.line_width() * self.style.width(segment),
+            self.color(segment.depth, segment.color, i, count),
+        );
+    }
+
+    fn fill(&self, mesh: &mut Mesh, polygon: &Polygon, i: usize) {
+        let color = self.color(polygon.depth, polygon.color, i, self.count);
+        mesh.polygon(&polygon.points, color);
     }
 
-    fn forward(&self, draw: &Draw, start_point: Point2, end_point: Point2) {
-        draw.line().start(start_point).end(end_point).color(GREEN);
+    fn color(&self, depth: usize, index: f32, i: usize, count: usize) -> style::Color {
+        self.style.color(depth, index, i, count, self.max_depth)
     }
+}
+
+// Candidates of an Evolver drawn side by side for picking favourites
+struct Gallery {
+    evolver: Evolver,
+    thumbnails: Vec<Mesh>,
+    favourites: Vec<bool>,
+}
+
+// Whether koch draws at most max segments, stopping at the first one past them
+fn fits(koch: &Koch, max: usize) -> bool {
+    let mut interpreter = Interpreter::new(1.0, koch.get_delta());
+    let mut segments = koch
+        .modules()
+        .filter(|module| matches!(interpreter.step(module), Some(Shape::Segment(_))));
+    segments.nth(max).is_none()
+}
+
+// Draws spec at the highest level within THUMBNAIL_SEGMENTS, scaled into a unit square.
+// Returns the level drawn along with the mesh.
+fn thumbnail(spec: &KochSpec) -> (Mesh, usize) {
+    let mut mesh = Mesh::default();
+    let mut koch = match Koch::try_from_spec(&KochSpec {
+        iterations: 0,
+        ..spec.clone()
+    }) {
+        Ok(koch) => koch,
+        Err(_) => return (mesh, 0),
+    };
+    let mut level = 0;
+    for n in 1..=spec.iterations {
+        if koch.check_n(n).is_err() {
+            break;
+        }
+        koch.set_n(n);
+        if !fits(&koch, THUMBNAIL_SEGMENTS) {
+            break;
+        }
+        level = n;
+    }
+    koch.set_n(level);
+    let geometry = interpret(&koch, 1.0);
+    let (min, max) = match geometry.bounds() {
+        Some(bounds) => bounds,
+        None => return (mesh, level),
+    };
+    let center = ((min + max) / 2.0).truncate();
+    let extent = (max - min).truncate().max_element().max(f32::EPSILON);
+    let place = |p: Vec3| ((p.truncate() - center) / extent).extend(0.0);
 
-    fn forward_no_line(&mut self, length: f32) {
-        self.xy += {
-            let angle = self.angle;
-            vec2(angle.cos(), angle.sin()) * length
+    let style = Style::new(&spec.gradient).unwrap_or_default();
+    let count = geometry.segments.len();
+    let max_depth = geometry.segments.iter().map(|s| s.depth).max().unwrap_or(0);
+    for (i, segment) in geometry.segments.iter().enumerate() {
+        mesh.line(
+            place(segment.start),
+            place(segment.end),
+            THUMBNAIL_WIDTH * style.width(segment),
+            style.color(segment.depth, segment.color, i, count, max_depth),
+        );
+    }
+    for polygon in geometry.polygons.iter() {
+        let points: Vec<_> = polygon.points.iter().map(|&p| place(p)).collect();
+        let color = style.color(polygon.depth, polygon.color, 0, count, max_depth);
+        mesh.polygon(&points, color);
+    }
+    (mesh, level)
+}
+
+impl Gallery {
+    fn new(spec: &KochSpec) -> Self {
+        let mut gallery = Self {
+            evolver: Evolver::new(spec, GRID * GRID, rand::random()),
+            thumbnails: Vec::new(),
+            favourites: vec![false; GRID * GRID],
         };
+        gallery.draw_thumbnails();
+        gallery
+    }
+
+    // Draws the thumbnails of the candidates past the ones already drawn, lowering their
+    // iterations to the level shown so that they can be opened and saved as they look
+    fn draw_thumbnails(&mut self) {
+        for i in self.thumbnails.len()..self.evolver.candidates().len() {
+            let (mesh, level) = thumbnail(&self.evolver.candidates()[i]);
+            self.evolver.set_iterations(i, level);
+            self.thumbnails.push(mesh);
+        }
     }
 
-    fn turn_left(&mut self, delta: f32) {
-        self.angle += delta;
+    fn favourites(&self) -> Vec<usize> {
+        (0..self.favourites.len())
+            .filter(|&i| self.favourites[i])
+            .collect()
     }
 
-    fn turn_right(&mut self, delta: f32) {
-        self.angle -= delta;
+    // Breeds the next generation, which starts with the favourites of this one
+    fn breed(&mut self) {
+        let favourites = self.favourites();
+        self.evolver.next_generation(&favourites);
+        self.thumbnails = favourites
+            .iter()
+            .map(|&i| std::mem::take(&mut self.thumbnails[i]))
+            .collect();
+        self.draw_thumbnails();
+        self.favourites = vec![false; GRID * GRID];
     }
 
-    fn push(&mut self) {
-        self.stack.push((self.xy, self.angle));
+    // Area of the window taken by the k-th candidate
+    fn cell(win: Rect, k: usize) -> Rect {
+        let room = win.pad(MARGIN).pad_top(MARGIN);
+        let (w, h) = (room.w() / GRID as f32, room.h() / GRID as f32);
+        let (row, col) = ((k / GRID) as f32, (k % GRID) as f32);
+        Rect::from_w_h(w, h)
+            .top_left_of(room)
+            .shift(vec2(col * w, -row * h))
     }
 
-    fn pop(&mut self) {
-        let (xy, angle) = self.stack.pop().unwrap();
-        self.xy = xy;
-        self.angle = angle;
+    fn toggle(&mut self, win: Rect, pos: Vec2) {
+        let k = (0..self.favourites.len()).find(|&k| Self::cell(win, k).contains(pos));
+        if let Some(k) = k {
+            self.favourites[k] = !self.favourites[k];
+        }
     }
 
-    fn reset(&mut self) {
-        while !self.stack.is_empty() {
-            self.pop();
+    fn draw(&self, app: &App, draw: &Draw) {
+        let win = app.window_rect();
+        for (k, mesh) in self.thumbnails.iter().enumerate() {
+            let cell = Self::cell(win, k).pad(8.0);
+            let color = if self.favourites[k] { WHITE } else { DIMGRAY };
+            draw.rect()
+                .xy(cell.xy())
+                .wh(cell.wh())
+                .no_fill()
+                .stroke(color)
+                .stroke_weight(1.0);
+            let size = cell.w().min(cell.h() - 20.0) * 0.9;
+            mesh.draw(
+                &draw.xy(cell.xy() + vec2(0.0, 10.0)).scale(size),
+                |p: Vec3| p.truncate(),
+            );
+            draw.text(&self.evolver.candidates()[k].name)
+                .color(color)
+                .font_size(12)
+                .w(cell.w())
+                .xy(cell.mid_bottom() + vec2(0.0, 10.0));
         }
-        self.push();
-        self.steps.clear();
+        let title = format!(
+            "generation {}: click to pick favourites, B breeds them, W saves them, \
+             Enter opens the first and E goes back",
+            self.evolver.get_generation()
+        );
+        draw.text(&title)
+            .color(GRAY)
+            .font_size(14)
+            .w(win.w() - 2.0 * MARGIN)
+            .xy(win.mid_top() - vec2(0.0, MARGIN));
     }
 }
 
 fn main() {
-    nannou::app(model).update(update).simple_window(view).run();
+    let args = Args::parse();
+    if let Some(Command::Stats { preset, n }) = &args.command {
+        match print_stats(preset, *n) {
+            Ok(()) => return,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &args.render {
+        match render(&args, path) {
+            Ok(()) => println!("saved {}", path.display()),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        if args.headless {
+            return;
+        }
+    }
+    nannou::app(model)
+        .update(update)
+        .exit(exit)
+        .simple_window(view)
+        .run();
+}
+
+// Prints the stats of each level up to n
+fn print_stats(name: &str, n: Option<usize>) -> Result<(), String> {
+    let spec = cli::find(name, &preset::presets(cli::presets_dir().as_deref()))?;
+    let mut koch = Koch::try_from_spec(&spec).map_err(|e| format!("{}: {}", spec.name, e))?;
+    if let Some(n) = n {
+        koch.check_n(n)
+            .map_err(|e| format!("{}: {}", spec.name, e))?;
+        koch.set_n(n);
+    }
+    println!("{}", spec.name);
+    print!("{}", analysis::table(&analysis::levels(&koch, 1.0)));
+    Ok(())
+}
+
+// Writes the finished drawing of the preset chosen by args to a .png or .svg file
+fn render(args: &Args, path: &Path) -> Result<(), Box<dyn Error>> {
+    let systems = preset::ifs_presets(cli::ifs_dir().as_deref());
+    let name = args.preset.as_deref().unwrap_or_default();
+    if let Some(spec) = systems.iter().find(|spec| spec.name == name) {
+        if path.extension().and_then(|e| e.to_str()) != Some("png") {
+            return Err("iterated function systems render to .png only".into());
+        }
+        let ifs = Ifs::try_from_spec(spec)?;
+        let (width, height) = args.size;
+        let seed = args.seed.unwrap_or_else(rand::random);
+        let mut game = ChaosGame::new(ifs, Style::new(&spec.gradient)?, width, height, seed);
+        game.play(RENDER_POINTS);
+        game.image().save(path)?;
+        return Ok(());
+    }
+    let spec = args.spec(&preset::presets(cli::presets_dir().as_deref()))?;
+    let mut koch = Koch::try_from_spec(&spec).map_err(|e| e.to_string())?;
+    koch.set_seed(args.seed.unwrap_or_else(rand::random));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("svg") => svg::export(&koch, args.length, path)?,
+        Some("png") => {
+            let mut turtle = Turtle::new(koch, args.length);
+            turtle.style = Style::new(&spec.gradient)?;
+            let (width, height) = args.size;
+            turtle.render(width, height).save(path)?;
+        }
+        _ => return Err("expected a .png or .svg file".into()),
+    }
+    Ok(())
 }
 
 struct Model {
     turtle: Turtle,
+    spec: KochSpec, // of the L-system shown, as loaded
+    presets: Vec<KochSpec>,
+    systems: Vec<IfsSpec>,    // listed after the presets
+    current: usize,           // index of the current preset, or system past the presets
+    chaos: Option<ChaosGame>, // shown instead of the turtle when a system is picked
+    size: (u32, u32),         // of the chaos game images
+    dir: Option<PathBuf>,     // where presets are loaded from and saved to
+    seed: Option<u64>,        // seed of every preset loaded, random if not given
+    gallery: Option<Gallery>,
+    escape: Option<Escape>, // shown instead of everything else when switched on
+    recording: Option<Recording>,
+    mouse: Vec2,           // last mouse position, for dragging
+    error: Option<String>, // shown over the drawing, e.g. why the last preset failed to load
+}
+
+// A recording of the turtle made from the command line: every stride-th frame until the
+// drawing is finished, or the finished drawing of each level from the axiom up
+struct Recording {
+    recorder: Recorder,
+    levels: Option<usize>, // last level, when recording one frame per level
+    gif: Option<PathBuf>,
+    duration: u32, // milliseconds each frame is shown in the GIF
+    done: bool,    // the last frame is captured, so quit once it is drawn
+}
+
+impl Model {
+    fn load_preset(&mut self, index: usize) {
+        self.current = index;
+        self.chaos = None;
+        match index.checked_sub(self.presets.len()) {
+            Some(i) => self.load_system(&self.systems[i].clone()),
+            None => self.load(&self.presets[index].clone()),
+        }
+    }
+
+    // Starts the chaos game of an iterated function system
+    fn load_system(&mut self, spec: &IfsSpec) {
+        let style = Style::new(&spec.gradient);
+        match (Ifs::try_from_spec(spec), style) {
+            (Ok(ifs), Ok(style)) => {
+                let (width, height) = self.size;
+                let seed = self.seed.unwrap_or_else(rand::random);
+                self.chaos = Some(ChaosGame::new(ifs, style, width, height, seed));
+                self.error = None;
+            }
+            (Err(e), _) | (_, Err(e)) => self.error = Some(format!("{}: {}", spec.name, e)),
+        }
+    }
+
+    // The density image of the chaos game, fit into the window, and its progress
+    fn draw_chaos(chaos: &ChaosGame, app: &App, draw: &Draw) {
+        let image = chaos.image();
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let image = nannou::image::DynamicImage::ImageRgb8(image);
+        let texture = wgpu::Texture::from_image(app, &image);
+        let win = app.window_rect();
+        let scale = (win.w() / width).min(win.h() / height);
+        draw.texture(&texture).w_h(width * scale, height * scale);
+
+        let text_rect = Rect::from_w_h(300.0, 100.0).top_left_of(win.pad(30.0));
+        draw.text(chaos.ifs.get_name())
+            .left_justify()
+            .align_text_top()
+            .color(WHITE)
+            .font_size(24)
+            .wh(text_rect.wh())
+            .xy(text_rect.xy());
+        draw.text(&format!("{} points", chaos.get_played()))
+            .left_justify()
+            .align_text_top()
+            .color(GRAY)
+            .font_size(14)
+            .wh(text_rect.wh())
+            .xy(text_rect.xy() - vec2(0.0, 32.0));
+    }
+
+    // The escape-time fractal filling the window, and how far it is zoomed
+    fn draw_escape(escape: &Escape, app: &App, draw: &Draw) {
+        let image = nannou::image::DynamicImage::ImageRgb8(escape.image());
+        let texture = wgpu::Texture::from_image(app, &image);
+        let win = app.window_rect();
+        draw.texture(&texture).wh(win.wh());
+
+        let text_rect = Rect::from_w_h(400.0, 100.0).top_left_of(win.pad(30.0));
+        draw.text(&escape.get_formula().to_string())
+            .left_justify()
+            .align_text_top()
+            .color(WHITE)
+            .font_size(24)
+            .wh(text_rect.wh())
+            .xy(text_rect.xy());
+        let progress = if escape.is_done() { "" } else { ", rendering" };
+        let info = format!(
+            "{} iterations, zoom {:.3e}{}",
+            escape.get_iterations(),
+            escape.get_zoom(),
+            progress
+        );
+        draw.text(&info)
+            .left_justify()
+            .align_text_top()
+            .color(GRAY)
+            .font_size(14)
+            .wh(text_rect.wh())
+            .xy(text_rect.xy() - vec2(0.0, 32.0));
+    }
+
+    // Shows the mandelbrot set in place of the drawing
+    fn open_escape(&mut self, win: Rect) {
+        let (width, height) = (win.w() as u32, win.h() as u32);
+        // a minimised window has nothing to render into
+        if width > 0 && height > 0 {
+            self.escape = Some(Escape::new(Formula::Mandelbrot, width, height));
+        }
+    }
+
+    // Keys of the escape-time fractal, or false if it is not shown
+    fn escape_key(&mut self, app: &App, key: Key) -> bool {
+        let escape = match &mut self.escape {
+            Some(escape) => escape,
+            None => return false,
+        };
+        match key {
+            Key::F => {
+                // the julia set of the point under the mouse follows the mandelbrot set
+                let formula = match escape.get_formula() {
+                    Formula::Mandelbrot => Formula::Julia(escape.point(app.mouse.position())),
+                    Formula::Julia(_) => Formula::BurningShip,
+                    Formula::BurningShip => Formula::Mandelbrot,
+                };
+                escape.set_formula(formula);
+            }
+            Key::Equals | Key::Plus | Key::NumpadAdd => {
+                escape.set_iterations(escape.get_iterations().saturating_mul(2))
+            }
+            Key::Minus | Key::NumpadSubtract => escape.set_iterations(escape.get_iterations() / 2),
+            Key::Key0 => escape.reset(),
+            Key::S => {
+                let dir = app.project_path().unwrap_or_else(|_| ".".into());
+                let name = match escape.get_formula() {
+                    Formula::Mandelbrot => "mandelbrot",
+                    Formula::Julia(_) => "julia",
+                    Formula::BurningShip => "burning_ship",
+                };
+                let path = dir.join(format!("{}.png", name));
+                match escape.image().save(&path) {
+                    Ok(()) => println!("saved {}", path.display()),
+                    Err(e) => self.error = Some(format!("{}: {}", path.display(), e)),
+                }
+            }
+            Key::X => self.escape = None,
+            _ => (),
+        }
+        true
+    }
+
+    fn load(&mut self, spec: &KochSpec) {
+        let style = match Style::new(&spec.gradient) {
+            Ok(style) => style,
+            Err(e) => {
+                self.error = Some(format!("{}: {}", spec.name, e));
+                return;
+            }
+        };
+        match Koch::try_from_spec(spec) {
+            Ok(mut koch) => {
+                koch.set_seed(self.seed.unwrap_or_else(rand::random));
+                let mode = self.turtle.style.mode;
+                self.turtle.set_koch(koch, Style { mode, ..style });
+                self.spec = spec.clone();
+                self.error = None;
+            }
+            Err(e) => self.error = Some(format!("{}: {}", spec.name, e)),
+        }
+    }
+
+    // Saves the favourites of the gallery as presets, replacing ones of the same name
+    fn save_favourites(&mut self) {
+        let gallery = match &self.gallery {
+            Some(gallery) => gallery,
+            None => return,
+        };
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => {
+                self.error = Some("no presets directory to save to".to_string());
+                return;
+            }
+        };
+        for i in gallery.favourites() {
+            let spec = &gallery.evolver.candidates()[i];
+            let path = dir.join(preset::file_name(&spec.name, "toml"));
+            if let Err(e) = preset::save(spec, &path) {
+                self.error = Some(format!("{}: {}", path.display(), e));
+                return;
+            }
+            println!("saved {}", path.display());
+            match self.presets.iter_mut().find(|p| p.name == spec.name) {
+                Some(p) => *p = spec.clone(),
+                None => self.presets.push(spec.clone()),
+            }
+        }
+    }
+
+    fn start_recording(&mut self, dir: &Path, args: &Args) {
+        let turtle = &mut self.turtle;
+        turtle.playback.hold = true;
+        let levels = args.levels.then(|| turtle.koch.get_n());
+        if args.levels {
+            turtle.playback.instant = true;
+            turtle.modify(|koch| koch.set_n(0));
+        }
+        self.recording = Some(Recording {
+            recorder: Recorder::new(dir, args.stride),
+            levels,
+            gif: args.gif.clone(),
+            duration: args.duration,
+            done: false,
+        });
+    }
+
+    // Draws the next frame of the recording and captures it, or quits after the last one
+    fn record(&mut self, app: &App) {
+        let recording = match &mut self.recording {
+            Some(recording) => recording,
+            None => return,
+        };
+        if recording.done {
+            return app.quit();
+        }
+        let turtle = &mut self.turtle;
+        if recording.levels.is_some() && turtle.finished {
+            // the last frame showed a finished level, so on to the next one
+            let next = turtle.koch.get_n() + 1;
+            turtle.modify(|koch| koch.set_n(next));
+        }
+        turtle.update(RECORD_STEP);
+        let path = match recording.levels {
+            Some(_) if !turtle.finished => None,
+            Some(_) => recording.recorder.tick(true),
+            None => recording.recorder.tick(turtle.finished),
+        };
+        if let Some(path) = path {
+            app.main_window().capture_frame(path);
+        }
+        let last = recording.levels.unwrap_or(0);
+        recording.done = turtle.finished && turtle.koch.get_n() >= last;
+    }
+
+    // Keys of the gallery, or false if it is not open
+    fn gallery_key(&mut self, key: Key) -> bool {
+        let gallery = match &mut self.gallery {
+            Some(gallery) => gallery,
+            None => return false,
+        };
+        match key {
+            Key::B => gallery.breed(),
+            Key::W => self.save_favourites(),
+            Key::Return => {
+                if let Some(&i) = gallery.favourites().first() {
+                    let spec = gallery.evolver.candidates()[i].clone();
+                    self.gallery = None;
+                    self.load(&spec);
+                }
+            }
+            Key::E => self.gallery = None,
+            _ => (),
+        }
+        true
+    }
+
+    fn draw_error(&self, app: &App, draw: &Draw) {
+        if let Some(error) = &self.error {
+            let win = app.window_rect();
+            let text_pos = Rect::from_w_h(win.w(), 100.0)
+                .mid_bottom_of(win.pad(30.0))
+                .xy();
+            draw.text(error)
+                .w(win.w() - 60.0)
+                .center_justify()
+                .color(RED)
+                .font_size(18)
+                .xy(text_pos);
+        }
+    }
 }
 
 fn model(app: &App) -> Model {
+    let args = Args::parse();
     let _ = app
         .new_window()
         .title(app.exe_name().unwrap())
-        .size(1024, 768)
+        .size(args.size.0, args.size.1)
         .view(view)
         .key_pressed(key_pressed)
+        .mouse_moved(mouse_moved)
+        .mouse_pressed(mouse_pressed)
+        .mouse_wheel(mouse_wheel)
         .build()
         .unwrap();
 
-    let turtle = Turtle::new(0.0, 0.0, 0.0, Koch::model(KochModel::Cyclone), 4.0);
-    Model { turtle }
+    let dir = app.assets_path().ok().map(|p| p.join("presets"));
+    let presets = preset::presets(dir.as_deref());
+    let systems = app.assets_path().ok().map(|p| p.join("ifs"));
+    let systems = preset::ifs_presets(systems.as_deref());
+    let spec = preset::builtin().remove(0);
+    let koch = Koch::try_from_spec(&spec).unwrap();
+    let mut turtle = Turtle::new(koch, args.length);
+    turtle.playback.speed = args.speed.min(MAX_SPEED);
+    let mut model = Model {
+        turtle,
+        spec,
+        presets,
+        systems,
+        current: 0,
+        chaos: None,
+        size: args.size,
+        dir,
+        seed: args.seed,
+        gallery: None,
+        escape: None,
+        recording: None,
+        mouse: Vec2::ZERO,
+        error: None,
+    };
+    // the preset given on the command line takes the place of the one of the same name
+    let name = args.preset.as_deref().unwrap_or_default();
+    if let Some(i) = model.systems.iter().position(|spec| spec.name == name) {
+        model.load_preset(model.presets.len() + i);
+        if args.record.is_some() {
+            model.error = Some("only L-systems can be recorded".to_string());
+        }
+        return model;
+    }
+    match args.spec(&model.presets) {
+        Ok(spec) => {
+            let index = match model.presets.iter().position(|p| p.name == spec.name) {
+                Some(index) => index,
+                None => {
+                    model.presets.push(spec.clone());
+                    model.presets.len() - 1
+                }
+            };
+            model.presets[index] = spec;
+            model.load_preset(index);
+        }
+        Err(e) => {
+            model.load_preset(0);
+            model.error = Some(e);
+        }
+    }
+    if let Some(dir) = &args.record {
+        model.start_recording(dir, &args);
+    }
+    model
 }
 
 fn key_pressed(app: &App, model: &mut Model, key: Key) {
+    if key == Key::Q {
+        app.quit();
+    }
+    if model.gallery_key(key) || model.escape_key(app, key) {
+        return;
+    }
+    let count = model.presets.len() + model.systems.len();
     match key {
-        Key::R => {
-            let koch_model: KochModel = rand::random();
-            let koch = Koch::model(koch_model);
-            model.turtle.set_koch(koch);
+        Key::R => return model.load_preset(random_range(0, count)),
+        Key::Left => return model.load_preset((model.current + count - 1) % count),
+        Key::Right => return model.load_preset((model.current + 1) % count),
+        Key::X => return model.open_escape(app.window_rect()),
+        _ => (),
+    }
+    if let Some(chaos) = &model.chaos {
+        // the other keys are for the turtle, but for saving the image
+        if key == Key::S {
+            let dir = app.project_path().unwrap_or_else(|_| ".".into());
+            let path = dir.join(preset::file_name(chaos.ifs.get_name(), "png"));
+            match chaos.image().save(&path) {
+                Ok(()) => println!("saved {}", path.display()),
+                Err(e) => model.error = Some(format!("{}: {}", path.display(), e)),
+            }
+        }
+        return;
+    }
+    match key {
+        Key::Equals | Key::Plus | Key::NumpadAdd => {
+            let n = model.turtle.koch.get_n() + 1;
+            match model.turtle.koch.check_n(n) {
+                Ok(()) => model.turtle.modify(|koch| koch.set_n(n)),
+                Err(e) => model.error = Some(e.to_string()),
+            }
+        }
+        Key::Minus | Key::NumpadSubtract => model
+            .turtle
+            .modify(|koch| koch.set_n(koch.get_n().saturating_sub(1))),
+        Key::LBracket => model
+            .turtle
+            .modify(|koch| koch.set_delta(koch.get_delta() - 1.0f32.to_radians())),
+        Key::RBracket => model
+            .turtle
+            .modify(|koch| koch.set_delta(koch.get_delta() + 1.0f32.to_radians())),
+        Key::S => {
+            let koch = &model.turtle.koch;
+            let dir = app.project_path().unwrap_or_else(|_| ".".into());
+            let path = dir.join(preset::file_name(koch.get_name(), "svg"));
+            match svg::export(koch, model.turtle.length, &path) {
+                Ok(()) => println!("saved {}", path.display()),
+                Err(e) => model.error = Some(format!("{}: {}", path.display(), e)),
+            }
+        }
+        Key::C => model.turtle.style.next_mode(),
+        Key::M => model.turtle.toggle_growth(),
+        Key::Comma => model.turtle.growth_seconds *= GROWTH_STEP,
+        Key::Period => model.turtle.growth_seconds /= GROWTH_STEP,
+        Key::Up => model.turtle.set_speed(true),
+        Key::Down => model.turtle.set_speed(false),
+        Key::I => model.turtle.playback.instant = !model.turtle.playback.instant,
+        Key::H => model.turtle.playback.hold = !model.turtle.playback.hold,
+        Key::Space => model.turtle.playback.paused = !model.turtle.playback.paused,
+        Key::P => model.turtle.perspective = !model.turtle.perspective,
+        Key::Key0 => model.turtle.reset_camera(),
+        Key::A => model.turtle.toggle_stats(),
+        // mutants are bred from the L-system as shown, and an iterated function system that
+        // failed to load has none
+        Key::E if model.current < model.presets.len() => {
+            let koch = &model.turtle.koch;
+            let angle = (koch.get_delta().to_degrees() * 1000.0).round() / 1000.0;
+            let spec = KochSpec {
+                iterations: koch.get_n(),
+                angle,
+                ..model.spec.clone()
+            };
+            model.gallery = Some(Gallery::new(&spec));
         }
-        Key::Q => app.quit(),
         _ => (),
     }
 }
 
-fn update(_app: &App, model: &mut Model, _update: Update) {
-    model.turtle.update();
+fn mouse_moved(app: &App, model: &mut Model, pos: Point2) {
+    if app.mouse.buttons.left().is_down() {
+        match &mut model.escape {
+            Some(escape) => escape.pan(pos - model.mouse),
+            None => model.turtle.pan += pos - model.mouse,
+        }
+    }
+    if app.mouse.buttons.right().is_down() {
+        model.turtle.orbit += (pos - model.mouse) * ORBIT_SPEED;
+    }
+    model.mouse = pos;
+}
+
+fn mouse_pressed(app: &App, model: &mut Model, button: MouseButton) {
+    if let (Some(gallery), MouseButton::Left) = (&mut model.gallery, button) {
+        gallery.toggle(app.window_rect(), app.mouse.position());
+    }
+}
+
+fn mouse_wheel(app: &App, model: &mut Model, delta: MouseScrollDelta, _phase: TouchPhase) {
+    let lines = match delta {
+        MouseScrollDelta::LineDelta(_, y) => y,
+        MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / 50.0,
+    };
+    match &mut model.escape {
+        Some(escape) => escape.zoom_at(app.mouse.position(), ZOOM_STEP.powf(lines) as f64),
+        None => model
+            .turtle
+            .zoom_at(app.mouse.position(), ZOOM_STEP.powf(lines)),
+    }
+}
+
+fn update(app: &App, model: &mut Model, update: Update) {
+    if model.recording.is_some() {
+        return model.record(app);
+    }
+    if let Some(escape) = &mut model.escape {
+        let win = app.window_rect();
+        escape.resize(win.w() as u32, win.h() as u32);
+        escape.refine(REFINE_BUDGET);
+        return;
+    }
+    if let Some(chaos) = &mut model.chaos {
+        if chaos.get_played() < CHAOS_LIMIT {
+            chaos.play(CHAOS_POINTS);
+        }
+        return;
+    }
+    let dt = update.since_last.as_secs_f32();
+    model.turtle.update(dt);
 }
 
 fn view(app: &App, model: &Model, frame: Frame) {
     let draw = app.draw();
     draw.background().color(BLACK);
-    model.turtle.draw(app, &draw);
+    match (&model.escape, &model.gallery, &model.chaos) {
+        (Some(escape), _, _) => Model::draw_escape(escape, app, &draw),
+        (None, Some(gallery), _) => gallery.draw(app, &draw),
+        (None, None, Some(chaos)) => Model::draw_chaos(chaos, app, &draw),
+        (None, None, None) => model.turtle.draw(app, &draw),
+    }
+    model.draw_error(app, &draw);
     draw.to_frame(app, &frame).unwrap();
 }
+
+// Waits for the frames of a recording to be written and encodes them into its GIF
+fn exit(app: &App, model: Model) {
+    let recording = match model.recording {
+        Some(recording) => recording,
+        None => return,
+    };
+    if app.main_window().await_capture_frame_jobs().is_err() {
+        eprintln!("timed out writing the recorded frames");
+    }
+    println!("recorded {} frames", recording.recorder.frames().len());
+    if let Some(path) = &recording.gif {
+        match record::encode_gif(recording.recorder.frames(), path, recording.duration) {
+            Ok(()) => println!("saved {}", path.display()),
+            Err(e) => eprintln!("{}: {}", path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::style::Color;
+
+use nannou::lyon::math::point;
+use nannou::lyon::path::Polygon;
+use nannou::lyon::tessellation::{
+    BuffersBuilder, FillOptions, FillTessellator, FillVertex, VertexBuffers,
+};
+use nannou::prelude::*;
+
+// Triangles of a drawing in drawing coordinates, projected onto the screen plane when drawn so
+// that the view can turn without tessellating again
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mesh {
+    vertices: Vec<Vertex>,
+    indices: Vec<usize>,
+}
+
+// A corner of a line is pushed sideways by offset, across the line to toward as it is seen on
+// the screen, so that lines keep their width from every side. Fills have no offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Vertex {
+    position: Vec3,
+    toward: Vec3,
+    offset: f32,
+    color: Color,
+}
+
+impl Mesh {
+    pub fn len(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // Adds a line from a to b as a quad of the given width
+    pub fn line(&mut self, a: Vec3, b: Vec3, width: f32, color: Color) {
+        if a == b {
+            return;
+        }
+        let first = self.vertices.len();
+        let half = width / 2.0;
+        for (position, toward, offset) in [(a, b, half), (a, b, -half), (b, a, half), (b, a, -half)]
+        {
+            self.vertices.push(Vertex {
+                position,
+                toward,
+                offset,
+                color,
+            });
+        }
+        self.indices
+            .extend([0, 1, 2, 0, 2, 3].iter().map(|i| first + i));
+    }
+
+    // Adds a filled polygon, which may be concave. It is tessellated in the plane it lies in.
+    pub fn polygon(&mut self, points: &[Vec3], color: Color) {
+        // Newell's normal, which averages the tilt of an outline that is not quite flat
+        let normal = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .fold(Vec3::ZERO, |n, (p, q)| n + p.cross(*q))
+            .normalize_or_zero();
+        if normal == Vec3::ZERO {
+            return;
+        }
+        let u = if normal.x.abs() < 0.9 {
+            Vec3::X
+        } else {
+            Vec3::Y
+        };
+        let u = u.cross(normal).normalize();
+        let v = normal.cross(u);
+        let origin = points[0];
+        let plane: Vec<_> = points
+            .iter()
+            .map(|&p| point((p - origin).dot(u), (p - origin).dot(v)))
+            .collect();
+        let polygon = Polygon {
+            points: &plane,
+            closed: true,
+        };
+        let mut buffers: VertexBuffers<Vec3, u32> = VertexBuffers::new();
+        let mut builder = BuffersBuilder::new(&mut buffers, |vertex: FillVertex| {
+            let p = vertex.position();
+            origin + u * p.x + v * p.y
+        });
+        // a degenerate outline just adds nothing
+        let options = FillOptions::default();
+        if FillTessellator::new()
+            .tessellate_polygon(polygon, &options, &mut builder)
+            .is_err()
+        {
+            return;
+        }
+        let first = self.vertices.len();
+        self.vertices
+            .extend(buffers.vertices.into_iter().map(|position| Vertex {
+                position,
+                toward: position,
+                offset: 0.0,
+                color,
+            }));
+        self.indices
+            .extend(buffers.indices.into_iter().map(|i| first + i as usize));
+    }
+
+    // The vertices on the screen plane, as placed by project
+    fn project(&self, project: impl Fn(Vec3) -> Vec2) -> Vec<(Vec2, Color)> {
+        self.vertices
+            .iter()
+            .map(|v| {
+                let p = project(v.position);
+                if v.offset == 0.0 {
+                    return (p, v.color);
+                }
+                let across = (project(v.toward) - p).normalize_or_zero().perp();
+                (p + across * v.offset, v.color)
+            })
+            .collect()
+    }
+
+    pub fn triangles(
+        &self,
+        project: impl Fn(Vec3) -> Vec2,
+    ) -> impl Iterator<Item = [(Vec2, Color); 3]> + '_ {
+        let vertices = self.project(project);
+        self.indices
+            .chunks(3)
+            .map(move |t| [t[0], t[1], t[2]].map(|i| vertices[i]))
+    }
+
+    pub fn draw(&self, draw: &Draw, project: impl Fn(Vec3) -> Vec2) {
+        let vertices = self
+            .project(project)
+            .into_iter()
+            .map(|(p, [r, g, b])| (p.extend(0.0), rgb(r, g, b)));
+        draw.mesh()
+            .indexed_colored(vertices, self.indices.iter().copied());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    const BLACK: Color = [0.0; 3];
+
+    fn flat(p: Vec3) -> Vec2 {
+        p.truncate()
+    }
+
+    #[test]
+    fn line() {
+        let mut mesh = Mesh::default();
+        mesh.line(vec3(0.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0), 1.0, BLACK);
+        assert_eq!(mesh.len(), 2);
+        let points: Vec<_> = mesh.project(flat).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(
+            points,
+            vec![
+                vec2(0.0, 0.5),
+                vec2(0.0, -0.5),
+                vec2(2.0, -0.5),
+                vec2(2.0, 0.5)
+            ]
+        );
+        // a zero length line has no direction to be widened in
+        mesh.line(vec3(1.0, 1.0, 0.0), vec3(1.0, 1.0, 0.0), 1.0, BLACK);
+        assert_eq!(mesh.len(), 2);
+
+        // turned to face the screen, a line along z keeps its width
+        let mut mesh = Mesh::default();
+        mesh.line(Vec3::ZERO, vec3(0.0, 0.0, 2.0), 1.0, BLACK);
+        let turn = |p: Vec3| flat(Quat::from_rotation_x(FRAC_PI_2) * p);
+        let points: Vec<_> = mesh.project(turn).into_iter().map(|(p, _)| p).collect();
+        assert!(points[0].abs_diff_eq(vec2(0.5, 0.0), 1e-5), "{:?}", points);
+        assert!(
+            points[2].abs_diff_eq(vec2(-0.5, -2.0), 1e-5),
+            "{:?}",
+            points
+        );
+    }
+
+    #[test]
+    fn polygon() {
+        // a concave arrowhead needs two triangles, whatever the order of its points
+        let points = [
+            vec2(0.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(0.0, 2.0),
+            vec2(1.0, 1.0),
+        ];
+        let area = |mesh: &Mesh, project: &dyn Fn(Vec3) -> Vec2| -> f32 {
+            mesh.triangles(project)
+                .map(|[a, b, c]| (b.0 - a.0).perp_dot(c.0 - a.0).abs() / 2.0)
+                .sum()
+        };
+        let mut mesh = Mesh::default();
+        mesh.polygon(&points.map(|p| p.extend(0.0)), BLACK);
+        assert_eq!(mesh.len(), 2);
+        // the notch at (1, 1) stays empty
+        let a = area(&mesh, &flat);
+        assert!((a - 1.0).abs() < 1e-5, "{}", a);
+
+        // one standing in the xz plane is seen edge on from the front, but whole from above
+        let mut mesh = Mesh::default();
+        mesh.polygon(&points.map(|p| vec3(p.x, 0.0, p.y)), BLACK);
+        assert_eq!(mesh.len(), 2);
+        assert!(area(&mesh, &flat).abs() < 1e-5);
+        let a = area(&mesh, &|p: Vec3| vec2(p.x, p.z));
+        assert!((a - 1.0).abs() < 1e-5, "{}", a);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::koch::interpreter::{Geometry, Interpreter, Segment, Shape};
+use crate::koch::Koch;
+
+use glam::Vec3;
+
+// Interpolates the drawing of level n - 1 into the drawing of level n. Each segment of level
+// n starts out as its share of the segment it was rewritten from, or as a point where the
+// parent module stood if that one drew nothing, so new branches sprout from their stems.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Morph {
+    segments: Vec<(Segment, Vec3, Vec3)>, // segments of level n with where their ends start
+    bounds: Option<(Vec3, Vec3)>,         // bounds of level n
+}
+
+// Maps points of one drawing onto another by matching their bounding boxes, so that levels
+// drawn at different sizes line up
+fn align(from: Option<(Vec3, Vec3)>, to: Option<(Vec3, Vec3)>) -> impl Fn(Vec3) -> Vec3 {
+    let frame = |(min, max): (Vec3, Vec3)| ((min + max) / 2.0, (max - min).max_element());
+    let (from, to) = match (from.map(frame), to.map(frame)) {
+        (Some(from), Some(to)) if from.1 > 0.0 => (from, to),
+        _ => ((Vec3::ZERO, 1.0), (Vec3::ZERO, 1.0)),
+    };
+    move |p| (p - from.0) / from.1 * to.1 + to.0
+}
+
+impl Morph {
+    pub fn new(koch: &Koch, length: f32) -> Self {
+        let generation = match koch.generation() {
+            Some(generation) => generation,
+            None => {
+                // nothing to grow from at the axiom, so it is shown as is
+                let mut interpreter = Interpreter::new(length, koch.get_delta());
+                let segments = koch
+                    .modules()
+                    .filter_map(|m| match interpreter.step(&m) {
+                        Some(Shape::Segment(s)) => Some((s, s.start, s.end)),
+                        _ => None,
+                    })
+                    .collect();
+                return Self::from_segments(segments);
+            }
+        };
+
+        // where the turtle stands before each parent module and what it draws
+        let mut interpreter = Interpreter::new(length, koch.get_delta());
+        let mut parents = Geometry::default();
+        let mut stands = Vec::with_capacity(generation.parents.len());
+        let mut drawn = Vec::with_capacity(generation.parents.len());
+        for module in generation.parents.iter() {
+            stands.push(interpreter.position());
+            match interpreter.step(module) {
+                Some(Shape::Segment(s)) => {
+                    drawn.push(Some((s.start, s.end)));
+                    parents.segments.push(s);
+                }
+                _ => drawn.push(None),
+            }
+        }
+
+        let mut interpreter = Interpreter::new(length, koch.get_delta());
+        let mut children = Geometry::default();
+        let mut origins = Vec::new();
+        for (module, &origin) in generation.children.iter().zip(generation.origins.iter()) {
+            if let Some(Shape::Segment(s)) = interpreter.step(module) {
+                children.segments.push(s);
+                origins.push(origin);
+            }
+        }
+
+        // segments of each family, to split the parent segment between them
+        let mut family = vec![0; drawn.len()];
+        for &origin in origins.iter() {
+            family[origin] += 1;
+        }
+        let map = align(parents.bounds(), children.bounds());
+        let mut seen = vec![0; drawn.len()];
+        let segments = children
+            .segments
+            .into_iter()
+            .zip(origins)
+            .map(|(segment, origin)| {
+                let (a, b) = drawn[origin].unwrap_or((stands[origin], stands[origin]));
+                let (a, b) = (map(a), map(b));
+                let (q, m) = (seen[origin] as f32, family[origin] as f32);
+                seen[origin] += 1;
+                (segment, a.lerp(b, q / m), a.lerp(b, (q + 1.0) / m))
+            })
+            .collect();
+        Self::from_segments(segments)
+    }
+
+    fn from_segments(segments: Vec<(Segment, Vec3, Vec3)>) -> Self {
+        let geometry = Geometry {
+            segments: segments.iter().map(|(s, _, _)| *s).collect(),
+            ..Geometry::default()
+        };
+        Self {
+            segments,
+            bounds: geometry.bounds(),
+        }
+    }
+
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        self.bounds
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    // The drawing t of the way from level n - 1 to level n
+    pub fn at(&self, t: f32) -> impl Iterator<Item = Segment> + '_ {
+        self.segments
+            .iter()
+            .map(move |&(segment, start, end)| Segment {
+                start: start.lerp(segment.start, t),
+                end: end.lerp(segment.end, t),
+                ..segment
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::fixtures::koch;
+
+    fn morph(axiom: &str, production: &str) -> Morph {
+        Morph::new(&koch(axiom, &[production], 1), 1.0)
+    }
+
+    fn ends(morph: &Morph, t: f32) -> Vec<[f32; 4]> {
+        morph
+            .at(t)
+            .map(|s| [s.start.x, s.start.y, s.end.x, s.end.y].map(|x| (x * 1e3).round() / 1e3))
+            .collect()
+    }
+
+    #[test]
+    fn split() {
+        // the parent segment is lined up with the bounds of the children and split in two
+        let morph = morph("F", "F => F+F-");
+        assert_eq!(
+            ends(&morph, 0.0),
+            vec![[0.0, 0.5, 0.5, 0.5], [0.5, 0.5, 1.0, 0.5]]
+        );
+        assert_eq!(
+            ends(&morph, 1.0),
+            vec![[0.0, 0.0, 1.0, 0.0], [1.0, 0.0, 1.0, 1.0]]
+        );
+    }
+
+    #[test]
+    fn sprout() {
+        // the branch grows out of where X stood, on the stretched stem
+        let morph = morph("FX", "X => [+F]F");
+        assert_eq!(
+            ends(&morph, 0.0),
+            vec![
+                [0.0, 0.5, 2.0, 0.5],
+                [2.0, 0.5, 2.0, 0.5],
+                [2.0, 0.5, 2.0, 0.5]
+            ]
+        );
+        assert_eq!(
+            ends(&morph, 0.5)[1..],
+            [[1.5, 0.25, 1.5, 0.75], [1.5, 0.25, 2.0, 0.25]]
+        );
+    }
+}
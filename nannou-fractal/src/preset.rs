@@ -0,0 +1,218 @@
+use crate::ifs::IfsSpec;
+use crate::koch::KochSpec;
+
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+const BUILTIN: [&str; 25] = [
+    include_str!("../assets/presets/cyclone.toml"),
+    include_str!("../assets/presets/caret.toml"),
+    include_str!("../assets/presets/islands.toml"),
+    include_str!("../assets/presets/xshape.toml"),
+    include_str!("../assets/presets/square.toml"),
+    include_str!("../assets/presets/grid.toml"),
+    include_str!("../assets/presets/sparse.toml"),
+    include_str!("../assets/presets/dense.toml"),
+    include_str!("../assets/presets/snowflake.toml"),
+    include_str!("../assets/presets/dragon.toml"),
+    include_str!("../assets/presets/sierpinski.toml"),
+    include_str!("../assets/presets/hex_gosper.toml"),
+    include_str!("../assets/presets/quad_gosper.toml"),
+    include_str!("../assets/presets/tree_a.toml"),
+    include_str!("../assets/presets/tree_b.toml"),
+    include_str!("../assets/presets/tree_c.toml"),
+    include_str!("../assets/presets/tree_d.toml"),
+    include_str!("../assets/presets/tree_e.toml"),
+    include_str!("../assets/presets/tree_f.toml"),
+    include_str!("../assets/presets/tree_stochastic.toml"),
+    include_str!("../assets/presets/tree_parametric.toml"),
+    include_str!("../assets/presets/tree_leaves.toml"),
+    include_str!("../assets/presets/tree_signal.toml"),
+    include_str!("../assets/presets/bush.toml"),
+    include_str!("../assets/presets/cantor.toml"),
+];
+
+const IFS_BUILTIN: [&str; 4] = [
+    include_str!("../assets/ifs/fern.toml"),
+    include_str!("../assets/ifs/sierpinski_chaos.toml"),
+    include_str!("../assets/ifs/heighway.toml"),
+    include_str!("../assets/ifs/maple.toml"),
+];
+
+// Presets compiled into the binary, so the app works without the assets directory
+pub fn builtin() -> Vec<KochSpec> {
+    BUILTIN
+        .iter()
+        .map(|s| toml::from_str(s).expect("invalid builtin preset"))
+        .collect()
+}
+
+pub fn ifs_builtin() -> Vec<IfsSpec> {
+    IFS_BUILTIN
+        .iter()
+        .map(|s| toml::from_str(s).expect("invalid builtin preset"))
+        .collect()
+}
+
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let s = fs::read_to_string(path)?;
+    Ok(toml::from_str(&s)?)
+}
+
+pub fn save(spec: &KochSpec, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, toml::to_string(spec)?)?;
+    Ok(())
+}
+
+// Name of the file to save a preset of the given name to, with path separators replaced so
+// that it stays in the directory it is joined to
+pub fn file_name(name: &str, extension: &str) -> String {
+    format!("{}.{}", name.replace(['/', '\\'], "_"), extension)
+}
+
+// Loads every .toml file in dir in file name order, skipping the ones that fail
+pub fn scan<T: DeserializeOwned>(dir: &Path) -> Vec<T> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|p| match load(p) {
+            Ok(spec) => Some(spec),
+            Err(e) => {
+                eprintln!("skipping preset {}: {}", p.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Builtin presets, replaced or extended by the ones of the same name found in dir
+fn overlay<T: DeserializeOwned>(
+    mut presets: Vec<T>,
+    dir: Option<&Path>,
+    name: fn(&T) -> &str,
+) -> Vec<T> {
+    for spec in dir.map(scan).unwrap_or_default() {
+        match presets.iter_mut().find(|p| name(p) == name(&spec)) {
+            Some(p) => *p = spec,
+            None => presets.push(spec),
+        }
+    }
+    presets
+}
+
+// The builtin presets, replaced or extended by the ones found in dir
+pub fn presets(dir: Option<&Path>) -> Vec<KochSpec> {
+    overlay(builtin(), dir, |spec| &spec.name)
+}
+
+// The builtin iterated function systems, replaced or extended by the ones found in dir
+pub fn ifs_presets(dir: Option<&Path>) -> Vec<IfsSpec> {
+    overlay(ifs_builtin(), dir, |spec| &spec.name)
+}
+
+// Fixtures shared by the tests of the other modules
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+    use crate::ifs::Ifs;
+    use crate::koch::Koch;
+
+    pub fn builtin_spec(name: &str) -> KochSpec {
+        builtin()
+            .into_iter()
+            .find(|spec| spec.name == name)
+            .unwrap()
+    }
+
+    pub fn builtin_koch(name: &str) -> Koch {
+        Koch::try_from_spec(&builtin_spec(name)).unwrap()
+    }
+
+    pub fn builtin_ifs(name: &str) -> Ifs {
+        let spec = ifs_builtin().into_iter().find(|spec| spec.name == name);
+        Ifs::try_from_spec(&spec.unwrap()).unwrap()
+    }
+
+    // A spec named test, with the default angle and alphabet
+    pub fn spec(axiom: &str, productions: &[&str], n: usize) -> KochSpec {
+        KochSpec {
+            name: "test".to_string(),
+            axiom: axiom.to_string(),
+            productions: productions.iter().map(|p| p.to_string()).collect(),
+            iterations: n,
+            ..KochSpec::default()
+        }
+    }
+
+    pub fn koch(axiom: &str, productions: &[&str], n: usize) -> Koch {
+        Koch::try_from_spec(&spec(axiom, productions, n)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn presets_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/presets")
+    }
+
+    #[test]
+    fn builtin_matches_assets() {
+        let builtin = builtin();
+        let mut scanned: Vec<KochSpec> = scan(&presets_dir());
+        assert_eq!(builtin.len(), scanned.len());
+        for spec in builtin {
+            let i = scanned.iter().position(|s| s.name == spec.name).unwrap();
+            assert_eq!(scanned.remove(i), spec);
+        }
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/ifs");
+        assert_eq!(scan::<IfsSpec>(&dir).len(), ifs_builtin().len());
+    }
+
+    #[test]
+    fn overlay() {
+        let name = format!("nannou-fractal-presets-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let spec = "name = \"%s\"\naxiom = \"F\"\nproductions = [\"F => FF\"]\niterations = 1\nangle = 90.0\n";
+        fs::write(dir.join("dragon.toml"), spec.replace("%s", "dragon")).unwrap();
+        fs::write(dir.join("line.toml"), spec.replace("%s", "line")).unwrap();
+        fs::write(dir.join("broken.toml"), "name = ").unwrap();
+
+        let presets = presets(Some(&dir));
+        assert_eq!(presets.len(), BUILTIN.len() + 1);
+        assert_eq!(presets[0].name, "cyclone");
+        let dragon = presets.iter().find(|p| p.name == "dragon").unwrap();
+        assert_eq!(dragon.axiom, "F");
+        assert_eq!(presets.last().unwrap().name, "line");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let name = format!("nannou-fractal-saved-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for spec in builtin() {
+            let path = dir.join(file_name(&spec.name, "toml"));
+            save(&spec, &path).unwrap();
+            assert_eq!(load::<KochSpec>(&path).unwrap(), spec);
+        }
+        let path = dir.join(file_name("../tree\\a", "toml"));
+        assert_eq!(path, dir.join(".._tree_a.toml"));
+        save(&builtin()[0], &path).unwrap();
+        assert_eq!(scan::<KochSpec>(&dir).len(), builtin().len() + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::style::Color;
+
+use glam::Vec2;
+use nannou::image::{Rgb, RgbImage};
+
+const SAMPLES: usize = 3; // samples per pixel along each axis, for smooth edges
+const BAND: usize = 16; // rows of pixels rasterized at a time, so that few samples are kept
+
+// Fills triangles given in pixel coordinates, y down, onto an image of the background colour.
+// The image is filled a band of rows at a time, each band resolved to pixels before the next.
+pub fn rasterize(
+    triangles: impl IntoIterator<Item = [(Vec2, Color); 3]>,
+    width: u32,
+    height: u32,
+    background: Color,
+) -> RgbImage {
+    let w = width as usize * SAMPLES;
+    // in samples, with their signed area
+    let triangles: Vec<_> = triangles
+        .into_iter()
+        .filter_map(|triangle| {
+            let [a, b, c] = triangle.map(|(p, _)| p * SAMPLES as f32);
+            let area = (b - a).perp_dot(c - a);
+            (area != 0.0).then_some(([a, b, c], area, triangle[0].1))
+        })
+        .collect();
+
+    let mut image = RgbImage::new(width, height);
+    let mut samples = Vec::new();
+    for top in (0..height as usize).step_by(BAND) {
+        let rows = BAND.min(height as usize - top);
+        let (y0, y1) = (top * SAMPLES, (top + rows) * SAMPLES);
+        samples.clear();
+        samples.resize(w * (y1 - y0), background);
+        for &([a, b, c], area, color) in triangles.iter() {
+            let min = a.min(b).min(c).max(Vec2::new(0.0, y0 as f32));
+            let max = a.max(b).max(c).min(Vec2::new(w as f32, y1 as f32));
+            for y in min.y as usize..max.y.ceil() as usize {
+                for x in min.x as usize..max.x.ceil() as usize {
+                    // the sample is inside when it is on the same side of every edge
+                    let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                    let edges = [
+                        (b - a).perp_dot(p - a),
+                        (c - b).perp_dot(p - b),
+                        (a - c).perp_dot(p - c),
+                    ];
+                    if edges.iter().all(|&e| e * area >= 0.0) {
+                        samples[(y - y0) * w + x] = color;
+                    }
+                }
+            }
+        }
+
+        for y in 0..rows {
+            for x in 0..width as usize {
+                let mut sum = [0.0; 3];
+                for dy in 0..SAMPLES {
+                    for dx in 0..SAMPLES {
+                        let sample = samples[(y * SAMPLES + dy) * w + x * SAMPLES + dx];
+                        for (sum, s) in sum.iter_mut().zip(sample) {
+                            *sum += s;
+                        }
+                    }
+                }
+                let count = (SAMPLES * SAMPLES) as f32;
+                let pixel = sum.map(|s| (s / count * 255.0).round().clamp(0.0, 255.0) as u8);
+                image.put_pixel(x as u32, (top + y) as u32, Rgb(pixel));
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangles() {
+        let white = [1.0; 3];
+        // the lower left half of the image, given clockwise
+        let triangle = [
+            (Vec2::new(0.0, 0.0), white),
+            (Vec2::new(4.0, 4.0), white),
+            (Vec2::new(0.0, 4.0), white),
+        ];
+        let image = rasterize([triangle], 4, 4, [0.0; 3]);
+        assert_eq!(image.get_pixel(0, 3), &Rgb([255; 3]));
+        assert_eq!(image.get_pixel(3, 0), &Rgb([0; 3]));
+        // pixels on the diagonal are partly covered
+        let Rgb([r, g, b]) = *image.get_pixel(1, 1);
+        assert!(r == g && g == b && r > 0 && r < 255, "{}", r);
+        // triangles reaching past the edges are clipped
+        let large = [
+            (Vec2::new(-10.0, -10.0), white),
+            (Vec2::new(30.0, -10.0), white),
+            (Vec2::new(-10.0, 30.0), white),
+        ];
+        let image = rasterize([large], 4, 4, [0.0; 3]);
+        assert!(image.pixels().all(|p| p == &Rgb([255; 3])));
+    }
+
+    #[test]
+    fn bands() {
+        // a triangle spanning several bands fills the same pixels as one band at a time would
+        let white = [1.0; 3];
+        let triangle = [
+            (Vec2::new(2.0, 1.0), white),
+            (Vec2::new(9.0, 50.0), white),
+            (Vec2::new(1.0, 40.0), white),
+        ];
+        let image = rasterize([triangle], 10, 60, [0.0; 3]);
+        assert_eq!(image.get_pixel(3, BAND as u32), &Rgb([255; 3]));
+        assert_eq!(image.get_pixel(4, 2 * BAND as u32 + 5), &Rgb([255; 3]));
+        assert_eq!(image.get_pixel(9, 10), &Rgb([0; 3]));
+        assert_eq!(image.get_pixel(4, 55), &Rgb([0; 3]));
+    }
+}
@@ -0,0 +1,100 @@
+use nannou::image::gif::{GifEncoder, Repeat};
+use nannou::image::{self, Delay, Frame};
+
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// Paths of the numbered frames of a recording, one for every stride-th frame drawn
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+    stride: usize,
+    ticks: usize, // frames drawn since the start
+    frames: Vec<PathBuf>,
+}
+
+impl Recorder {
+    pub fn new(dir: &Path, stride: usize) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            stride: stride.max(1),
+            ticks: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    // Counts a frame, and names the file to capture it to if it falls on the stride or if
+    // forced, e.g. for the last frame
+    pub fn tick(&mut self, force: bool) -> Option<PathBuf> {
+        let capture = force || self.ticks.is_multiple_of(self.stride);
+        self.ticks += 1;
+        if !capture {
+            return None;
+        }
+        let path = self.dir.join(format!("frame_{:05}.png", self.frames.len()));
+        self.frames.push(path.clone());
+        Some(path)
+    }
+
+    pub fn frames(&self) -> &[PathBuf] {
+        &self.frames
+    }
+}
+
+// Encodes the frames into a looping GIF, showing each for the given milliseconds
+pub fn encode_gif(frames: &[PathBuf], path: &Path, duration: u32) -> Result<(), Box<dyn Error>> {
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(duration, 1);
+    for frame in frames {
+        let image = image::open(frame)
+            .map_err(|e| format!("{}: {}", frame.display(), e))?
+            .to_rgba8();
+        encoder.encode_frame(Frame::from_parts(image, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nannou::image::gif::GifDecoder;
+    use nannou::image::{AnimationDecoder, Rgb, RgbImage};
+
+    #[test]
+    fn stride() {
+        let mut recorder = Recorder::new(Path::new("frames"), 3);
+        let captured: Vec<_> = (0..8).map(|i| recorder.tick(i == 7).is_some()).collect();
+        assert_eq!(
+            captured,
+            [true, false, false, true, false, false, true, true]
+        );
+        assert_eq!(recorder.frames().len(), 4);
+        assert_eq!(recorder.frames()[3], Path::new("frames/frame_00003.png"));
+    }
+
+    #[test]
+    fn gif() {
+        let dir = std::env::temp_dir().join(format!("nannou-fractal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut recorder = Recorder::new(&dir, 1);
+        for shade in [0, 128, 255] {
+            let path = recorder.tick(false).unwrap();
+            RgbImage::from_pixel(8, 4, Rgb([shade; 3]))
+                .save(&path)
+                .unwrap();
+        }
+        let path = dir.join("recording.gif");
+        encode_gif(recorder.frames(), &path, 100).unwrap();
+
+        let decoder = GifDecoder::new(File::open(&path).unwrap()).unwrap();
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].delay().numer_denom_ms(), (100, 1));
+        assert_eq!(frames[2].buffer().dimensions(), (8, 4));
+        assert_eq!(frames[2].buffer().get_pixel(0, 0).0, [255; 4]);
+    }
+}
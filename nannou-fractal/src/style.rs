@@ -0,0 +1,156 @@
+use crate::koch::interpreter::Segment;
+
+use std::fmt;
+
+pub type Color = [f32; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Depth, // along the gradient from the trunk to the deepest branch
+    Step,  // along the gradient in drawing order
+    Index, // gradient entries picked by the ' command
+}
+
+// Stroke width and colour of the segments of a drawing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Style {
+    pub gradient: Vec<Color>,
+    pub mode: ColorMode,
+    pub decay: f32, // share of the width kept by each level of branching
+}
+
+const DEFAULT_GRADIENT: [&str; 3] = ["#6b4226", "#3c8d2f", "#a8d46f"];
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColorMode::Depth => write!(f, "depth"),
+            ColorMode::Step => write!(f, "step"),
+            ColorMode::Index => write!(f, "index"),
+        }
+    }
+}
+
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s
+        .strip_prefix('#')
+        .filter(|h| h.len() == 6 && h.is_ascii())
+        .ok_or_else(|| format!("bad colour `{}`, expected #rrggbb", s))?;
+    let mut color = [0.0; 3];
+    for (c, i) in color.iter_mut().zip([0, 2, 4]) {
+        let v = u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| format!("bad colour `{}`, expected #rrggbb", s))?;
+        *c = v as f32 / 255.0;
+    }
+    Ok(color)
+}
+
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t)
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self::new(&[]).unwrap()
+    }
+}
+
+impl Style {
+    // Builds a style from the gradient of a spec, falling back to the default for none
+    pub fn new(gradient: &[String]) -> Result<Self, String> {
+        let gradient: Result<Vec<Color>, String> = if gradient.is_empty() {
+            DEFAULT_GRADIENT.iter().map(|c| parse_color(c)).collect()
+        } else {
+            gradient.iter().map(|c| parse_color(c)).collect()
+        };
+        Ok(Self {
+            gradient: gradient?,
+            mode: ColorMode::Depth,
+            decay: 0.75,
+        })
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = match self.mode {
+            ColorMode::Depth => ColorMode::Step,
+            ColorMode::Step => ColorMode::Index,
+            ColorMode::Index => ColorMode::Depth,
+        };
+    }
+
+    // Width relative to the trunk
+    pub fn width(&self, segment: &Segment) -> f32 {
+        segment.width * self.decay.powi(segment.depth as i32)
+    }
+
+    // Colour of a shape at the given depth and colour index, drawn after i of count segments.
+    // max_depth is the deepest branch of the drawing.
+    pub fn color(
+        &self,
+        depth: usize,
+        index: f32,
+        i: usize,
+        count: usize,
+        max_depth: usize,
+    ) -> Color {
+        let ratio = |a: usize, b: usize| if b == 0 { 0.0 } else { a as f32 / b as f32 };
+        match self.mode {
+            ColorMode::Depth => self.sample(ratio(depth, max_depth)),
+            ColorMode::Step => self.sample(ratio(i, count.saturating_sub(1))),
+            ColorMode::Index => self.gradient[index.max(0.0) as usize % self.gradient.len()],
+        }
+    }
+
+    // Interpolates the gradient at t in [0, 1]
+    pub fn sample(&self, t: f32) -> Color {
+        let last = self.gradient.len() - 1;
+        let x = t.clamp(0.0, 1.0) * last as f32;
+        let i = (x.floor() as usize).min(last.saturating_sub(1));
+        match self.gradient.get(i + 1) {
+            Some(&next) => mix(self.gradient[i], next, x - i as f32),
+            None => self.gradient[i],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    fn segment(depth: usize) -> Segment {
+        Segment {
+            start: Vec3::ZERO,
+            end: Vec3::X,
+            depth,
+            step: 0,
+            width: 1.0,
+            color: 0.0,
+        }
+    }
+
+    fn from(gradient: &[&str]) -> Result<Style, String> {
+        let gradient: Vec<String> = gradient.iter().map(|c| c.to_string()).collect();
+        Style::new(&gradient)
+    }
+
+    #[test]
+    fn gradient() {
+        let mut style = from(&["#000000", "#ff0000", "#ffffff"]).unwrap();
+        assert_eq!(style.color(0, 0.0, 0, 1, 4), [0.0, 0.0, 0.0]);
+        assert_eq!(style.color(1, 0.0, 0, 1, 4), [0.5, 0.0, 0.0]);
+        assert_eq!(style.color(4, 0.0, 0, 1, 4), [1.0, 1.0, 1.0]);
+        style.next_mode();
+        assert_eq!(style.color(0, 0.0, 2, 5, 4), [1.0, 0.0, 0.0]);
+        style.next_mode();
+        assert_eq!(style.color(0, 4.0, 0, 1, 4), [1.0, 0.0, 0.0]);
+        assert_eq!(style.width(&segment(2)), 0.75 * 0.75);
+
+        let single = from(&["#ffffff"]).unwrap();
+        assert_eq!(single.color(3, 0.0, 0, 1, 4), [1.0, 1.0, 1.0]);
+        assert_eq!(
+            from(&["red"]).unwrap_err(),
+            "bad colour `red`, expected #rrggbb"
+        );
+    }
+}
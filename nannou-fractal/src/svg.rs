@@ -0,0 +1,113 @@
+use crate::koch::interpreter::interpret;
+use crate::koch::Koch;
+
+use glam::Vec3;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn number(x: f32) -> String {
+    // avoid printing -0.000 for the rounding noise of the turtle
+    format!("{:.3}", if x.abs() < 5e-4 { 0.0 } else { x })
+}
+
+// Escapes the characters that would end or break XML text and attribute values
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn points(points: &[Vec3]) -> String {
+    let points: Vec<String> = points
+        .iter()
+        .map(|p| format!("{},{}", number(p.x), number(-p.y)))
+        .collect();
+    points.join(" ")
+}
+
+// Renders the interpreted polylines in SVG coordinates, where y points down.
+// 3D drawings are projected onto the xy plane by dropping z.
+pub fn to_svg(koch: &Koch, length: f32) -> String {
+    let geometry = interpret(koch, length);
+    let (min, max) = geometry.bounds().unwrap_or((Vec3::ZERO, Vec3::ZERO));
+    let margin = length;
+    let (x, y) = (min.x - margin, -max.y - margin);
+    let (w, h) = (max.x - min.x + 2.0 * margin, max.y - min.y + 2.0 * margin);
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w:.3}" height="{h:.3}" viewBox="{x:.3} {y:.3} {w:.3} {h:.3}">"#
+    )
+    .unwrap();
+    writeln!(svg, "<title>{}</title>", escape(koch.get_name())).unwrap();
+    writeln!(
+        svg,
+        r#"<g fill="none" stroke="black" stroke-width="{:.3}" stroke-linecap="round" stroke-linejoin="round">"#,
+        length / 4.0
+    )
+    .unwrap();
+    for line in geometry.polylines {
+        writeln!(svg, r#"<polyline points="{}"/>"#, points(&line.points)).unwrap();
+    }
+    writeln!(svg, "</g>").unwrap();
+    if !geometry.polygons.is_empty() {
+        writeln!(svg, r#"<g fill="black" stroke="none">"#).unwrap();
+        for polygon in geometry.polygons {
+            writeln!(svg, r#"<polygon points="{}"/>"#, points(&polygon.points)).unwrap();
+        }
+        writeln!(svg, "</g>").unwrap();
+    }
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+pub fn export(koch: &Koch, length: f32, path: &Path) -> io::Result<()> {
+    fs::write(path, to_svg(koch, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::koch::KochSpec;
+    use crate::preset::fixtures::{builtin_koch, koch, spec};
+
+    #[test]
+    fn bounding_box() {
+        let svg = to_svg(&koch("F+F+F", &[], 0), 10.0);
+        assert!(svg.contains(r#"viewBox="-10.000 -20.000 30.000 30.000""#));
+        assert_eq!(svg.matches("<polyline").count(), 1);
+    }
+
+    #[test]
+    fn title() {
+        let spec = KochSpec {
+            name: "<trees> & \"bushes\"".to_string(),
+            ..spec("F", &[], 0)
+        };
+        let svg = to_svg(&Koch::try_from_spec(&spec).unwrap(), 10.0);
+        assert!(svg.contains("<title>&lt;trees&gt; &amp; &quot;bushes&quot;</title>"));
+    }
+
+    #[test]
+    fn polygons() {
+        let svg = to_svg(&koch("{.f.+f.+f.}", &[], 0), 10.0);
+        assert!(svg.contains(
+            r#"<polygon points="0.000,0.000 10.000,0.000 10.000,-10.000 0.000,-10.000"/>"#
+        ));
+        assert_eq!(svg.matches("<polyline").count(), 0);
+
+        let svg = to_svg(&builtin_koch("tree_leaves"), 4.0);
+        assert!(svg.matches("<polygon").count() > 100);
+    }
+}